@@ -1,3 +1,5 @@
 fn main() {
-    prost_build::compile_protos(&["src/messages.proto"], &["src/"]).unwrap();
+    tonic_prost_build::configure()
+        .compile_protos(&["src/messages.proto"], &["src/"])
+        .unwrap();
 }
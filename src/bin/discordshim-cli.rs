@@ -0,0 +1,436 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand, builder::FalseyValueParser};
+use color_eyre::{eyre, eyre::eyre};
+use discordshim::{
+    client::{Client, ClientOptions, Endpoint},
+    embedbuilder::{AttachmentLimits, split_file},
+    messages::{EmbedContent, ProtoFile, Request, Settings, TextField, request::Message},
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+/// Post to discord through a DiscordShim, or print the commands sent to a channel.
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Args)]
+struct ConnectionArgs {
+    /// Address of the shim
+    #[arg(long, env = "DISCORDSHIM_ADDRESS", default_value = "127.0.0.1:23416")]
+    address: String,
+    /// Connect to the shim's Unix socket instead
+    #[arg(long, env = "DISCORDSHIM_UNIX_SOCKET", conflicts_with = "tls")]
+    unix_socket: Option<PathBuf>,
+    /// Connect over TLS
+    #[arg(long, env = "DISCORDSHIM_TLS", value_parser = FalseyValueParser::new())]
+    tls: bool,
+    /// PEM CA to verify the shim with, instead of the public web roots
+    #[arg(long, env = "DISCORDSHIM_TLS_CA")]
+    tls_ca: Option<PathBuf>,
+    /// PEM certificate chain to present, if the shim requires one
+    #[arg(long, env = "DISCORDSHIM_CLIENT_CERT", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+    /// PEM private key for the client certificate
+    #[arg(long, env = "DISCORDSHIM_CLIENT_KEY", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+    /// Discord channel to post to or listen in
+    #[arg(long, env = "DISCORDSHIM_CHANNEL_ID")]
+    channel: u64,
+    /// Code from /pair, unless the shim trusts the channel
+    #[arg(long, env = "DISCORDSHIM_PAIRING_CODE", hide_env_values = true)]
+    pairing_code: Option<String>,
+}
+
+impl ConnectionArgs {
+    fn endpoint(self) -> eyre::Result<Endpoint> {
+        if let Some(path) = self.unix_socket {
+            return Ok(Endpoint::Unix(path));
+        }
+        if !self.tls {
+            return Ok(Endpoint::Tcp(self.address));
+        }
+        let identity = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
+        };
+        Endpoint::tls(self.address, self.tls_ca.as_deref(), identity)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Post an embed, printing the IDs of the messages posted
+    Embed(Box<EmbedArgs>),
+    /// Upload files, zipping and splitting large ones, printing the IDs of the messages posted
+    File {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Files this size or larger are zipped and split
+        #[arg(long, default_value_t = AttachmentLimits::default().max_attachment_size)]
+        max_attachment_size: usize,
+        /// Size of each part of a split file
+        #[arg(long, default_value_t = AttachmentLimits::default().chunk_size)]
+        chunk_size: usize,
+    },
+    /// Print the commands sent to the channel as JSON lines, reconnecting if the connection drops
+    Listen {
+        /// Only print commands with this prefix, without it. Every message is printed by default
+        #[arg(long, default_value = "")]
+        prefix: String,
+    },
+}
+
+#[derive(clap::Args)]
+struct EmbedArgs {
+    /// JSON or TOML file describing the embed, the other flags override it
+    #[arg(long)]
+    from: Option<PathBuf>,
+    #[arg(long)]
+    title: Option<String>,
+    #[arg(long)]
+    description: Option<String>,
+    #[arg(long)]
+    author: Option<String>,
+    /// Decimal, or hex like #ff8800
+    #[arg(long, value_parser = parse_color)]
+    color: Option<i32>,
+    /// A field as name=value, may be repeated
+    #[arg(long = "field", value_parser = parse_field)]
+    fields: Vec<EmbedField>,
+    /// Show the fields added with --field side by side
+    #[arg(long)]
+    inline: bool,
+    #[arg(long)]
+    footer: Option<String>,
+    /// Link on the title
+    #[arg(long)]
+    url: Option<String>,
+    /// Image file to attach as the embed's image
+    #[arg(long)]
+    image: Option<PathBuf>,
+    #[arg(long)]
+    image_url: Option<String>,
+    #[arg(long)]
+    thumbnail_url: Option<String>,
+}
+
+impl EmbedArgs {
+    fn spec(self) -> eyre::Result<EmbedSpec> {
+        let mut spec = match &self.from {
+            Some(path) => EmbedSpec::load(path)?,
+            None => EmbedSpec::default(),
+        };
+        let overrides = [
+            (self.title, &mut spec.title),
+            (self.description, &mut spec.description),
+            (self.author, &mut spec.author),
+            (self.footer, &mut spec.footer),
+            (self.url, &mut spec.url),
+            (self.image_url, &mut spec.image_url),
+            (self.thumbnail_url, &mut spec.thumbnail_url),
+        ];
+        for (value, field) in overrides {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        if let Some(color) = self.color {
+            spec.color = color;
+        }
+        if self.image.is_some() {
+            spec.image = self.image;
+        }
+        for mut field in self.fields {
+            field.inline = self.inline;
+            spec.fields.push(field);
+        }
+        Ok(spec)
+    }
+}
+
+/// An embed as written in a JSON or TOML file. Image paths are relative to the file.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EmbedSpec {
+    title: String,
+    description: String,
+    url: String,
+    author: String,
+    author_url: String,
+    author_icon_url: String,
+    #[serde(deserialize_with = "deserialize_color")]
+    color: i32,
+    fields: Vec<EmbedField>,
+    footer: String,
+    footer_icon_url: String,
+    /// Unix time in seconds, 0 for none.
+    timestamp: i64,
+    image: Option<PathBuf>,
+    image_url: String,
+    thumbnail: Option<PathBuf>,
+    thumbnail_url: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmbedField {
+    name: String,
+    value: String,
+    #[serde(default)]
+    inline: bool,
+}
+
+impl EmbedSpec {
+    fn load(path: &Path) -> eyre::Result<EmbedSpec> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| eyre!("Failed to read {}: {e}", path.display()))?;
+        let mut spec: EmbedSpec = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for image in [&mut spec.image, &mut spec.thumbnail].into_iter().flatten() {
+            *image = dir.join(&*image);
+        }
+        Ok(spec)
+    }
+
+    fn into_embed(self) -> eyre::Result<EmbedContent> {
+        Ok(EmbedContent {
+            title: self.title,
+            description: self.description,
+            url: self.url,
+            author: self.author,
+            author_url: self.author_url,
+            author_icon_url: self.author_icon_url,
+            color: self.color,
+            textfield: self
+                .fields
+                .into_iter()
+                .map(|field| TextField {
+                    title: field.name,
+                    text: field.value,
+                    inline: field.inline,
+                })
+                .collect(),
+            footer: self.footer,
+            footer_icon_url: self.footer_icon_url,
+            timestamp: self.timestamp,
+            snapshot: self.image.as_deref().map(read_file).transpose()?,
+            image_url: self.image_url,
+            thumbnail: self.thumbnail.as_deref().map(read_file).transpose()?,
+            thumbnail_url: self.thumbnail_url,
+            ..Default::default()
+        })
+    }
+}
+
+fn read_file(path: &Path) -> eyre::Result<ProtoFile> {
+    let data = fs::read(path).map_err(|e| eyre!("Failed to read {}: {e}", path.display()))?;
+    let filename = path
+        .file_name()
+        .ok_or_else(|| eyre!("{} is not a file", path.display()))?;
+    Ok(ProtoFile {
+        data,
+        filename: filename.to_string_lossy().into_owned(),
+    })
+}
+
+fn parse_color(value: &str) -> Result<i32, String> {
+    let parsed = match value.strip_prefix('#').or_else(|| value.strip_prefix("0x")) {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    match parsed {
+        Ok(color) if (0..=0xffffff).contains(&color) => Ok(color),
+        _ => Err(format!("{value} is not a colour like #ff8800")),
+    }
+}
+
+fn deserialize_color<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Color {
+        Number(i32),
+        Text(String),
+    }
+    match Color::deserialize(deserializer)? {
+        Color::Number(color) => parse_color(&color.to_string()),
+        Color::Text(color) => parse_color(&color),
+    }
+    .map_err(serde::de::Error::custom)
+}
+
+fn parse_field(value: &str) -> Result<EmbedField, String> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("{value} is not a field like name=value"))?;
+    Ok(EmbedField {
+        name: name.to_string(),
+        value: value.to_string(),
+        inline: false,
+    })
+}
+
+/// A command sent to the channel, as printed by `listen`.
+#[derive(Serialize)]
+struct CommandLine {
+    command: String,
+    user: u64,
+    username: String,
+    display_name: String,
+    channel_id: u64,
+    guild_id: u64,
+    message_id: u64,
+    timestamp: i64,
+}
+
+impl CommandLine {
+    fn new(request: Request) -> Option<CommandLine> {
+        let Some(Message::Command(command)) = request.message else {
+            return None;
+        };
+        let info = request.info.unwrap_or_default();
+        Some(CommandLine {
+            command,
+            user: request.user,
+            username: info.username,
+            display_name: info.display_name,
+            channel_id: info.channel_id,
+            guild_id: info.guild_id,
+            message_id: info.message_id,
+            timestamp: info.timestamp,
+        })
+    }
+}
+
+async fn listen(client: Client) -> eyre::Result<()> {
+    let mut requests = client.requests();
+    while let Some(request) = requests.next().await {
+        if let Some(line) = CommandLine::new(request) {
+            println!("{}", serde_json::to_string(&line)?);
+        }
+    }
+    Err(eyre!("Disconnected from the shim"))
+}
+
+fn print_ids(ids: &[u64]) {
+    for id in ids {
+        println!("{id}");
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    pretty_env_logger::init();
+    let args = Args::parse();
+
+    let mut settings = Settings {
+        channel_id: args.connection.channel,
+        pairing_code: args.connection.pairing_code.clone().unwrap_or_default(),
+        ..Default::default()
+    };
+    if let Command::Listen { prefix } = &args.command {
+        settings.command_prefix = prefix.clone();
+    }
+    let mut options = ClientOptions::new(args.connection.endpoint()?, settings);
+    options.name = String::from("discordshim-cli");
+    // Only listening is worth reconnecting for, a one-off post should just fail.
+    if !matches!(args.command, Command::Listen { .. }) {
+        options.reconnect = None;
+    }
+    let client = Client::connect(options).await?;
+
+    match args.command {
+        Command::Embed(embed) => {
+            let embed = embed.spec()?.into_embed()?;
+            print_ids(&client.send_embed(embed).await?);
+        }
+        Command::File {
+            files,
+            max_attachment_size,
+            chunk_size,
+        } => {
+            let limits = AttachmentLimits {
+                max_attachment_size,
+                chunk_size,
+            };
+            for path in files {
+                let file = read_file(&path)?;
+                // Split here too, so files larger than the shim's frame limit can be sent.
+                for (filename, attachment) in split_file(file.filename, &file.data, &limits) {
+                    print_ids(&client.send_file(filename, attachment.data).await?);
+                }
+            }
+        }
+        Command::Listen { .. } => listen(client).await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{EmbedField, EmbedSpec, parse_color, parse_field};
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(Ok(0xff8800), parse_color("#ff8800"));
+        assert_eq!(Ok(0xff8800), parse_color("0xff8800"));
+        assert_eq!(Ok(255), parse_color("255"));
+        assert!(parse_color("#1000000").is_err());
+        assert!(parse_color("orange").is_err());
+    }
+
+    #[test]
+    fn test_parse_field() {
+        let field = parse_field("Progress=50% = halfway").unwrap();
+        assert_eq!("Progress", field.name);
+        assert_eq!("50% = halfway", field.value);
+        assert!(parse_field("Progress").is_err());
+    }
+
+    #[test]
+    fn test_spec_formats() {
+        let toml = r##"
+            title = "Print done"
+            color = "#00ff00"
+            image = "snapshot.jpg"
+
+            [[fields]]
+            name = "Time"
+            value = "1h"
+            inline = true
+        "##;
+        let json = r##"{
+            "title": "Print done",
+            "color": 65280,
+            "image": "snapshot.jpg",
+            "fields": [{"name": "Time", "value": "1h", "inline": true}]
+        }"##;
+        let expected = EmbedSpec {
+            title: "Print done".to_string(),
+            color: 0x00ff00,
+            image: Some(PathBuf::from("snapshot.jpg")),
+            fields: vec![EmbedField {
+                name: "Time".to_string(),
+                value: "1h".to_string(),
+                inline: true,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(expected, toml::from_str::<EmbedSpec>(toml).unwrap());
+        assert_eq!(expected, serde_json::from_str::<EmbedSpec>(json).unwrap());
+        assert!(toml::from_str::<EmbedSpec>("colour = 1").is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use async_std::{net::TcpListener, task};
+use clap::Parser;
+use color_eyre::{eyre, eyre::eyre};
+use discordshim::{
+    client::{Client, ClientOptions, Endpoint},
+    config::Config,
+    fake::FakeDiscord,
+    messages::{EmbedContent, Error, ProtoFile, Settings, TextField},
+    server::Server,
+};
+use log::warn;
+use serenity::model::id::{ChannelId, GuildId};
+
+/// Simulate many printers against a shim, reporting ack latency, throughput and disconnects.
+///
+/// The channels used must be trusted by the shim, or run with --fake to load an in-process shim
+/// backed by a fake discord instead.
+#[derive(Parser)]
+struct Args {
+    /// Address of the shim
+    #[arg(long, env = "DISCORDSHIM_ADDRESS", default_value = "127.0.0.1:23416")]
+    address: String,
+    /// Connect to the shim's Unix socket instead
+    #[arg(long, env = "DISCORDSHIM_UNIX_SOCKET", conflicts_with = "tls")]
+    unix_socket: Option<PathBuf>,
+    /// Connect over TLS
+    #[arg(long)]
+    tls: bool,
+    /// PEM CA to verify the shim with, instead of the public web roots
+    #[arg(long, env = "DISCORDSHIM_TLS_CA")]
+    tls_ca: Option<PathBuf>,
+    /// Run a shim in-process against a fake discord, using the [server] section of this config if
+    /// given, and load it instead
+    #[arg(long)]
+    fake: bool,
+    #[arg(long, requires = "fake")]
+    config: Option<PathBuf>,
+    /// Number of simulated printers
+    #[arg(long, default_value_t = 100)]
+    clients: usize,
+    /// Seconds over which the clients connect
+    #[arg(long, default_value_t = 10.0)]
+    ramp_up: f64,
+    /// Seconds to run for once every client has started
+    #[arg(long, default_value_t = 60.0)]
+    duration: f64,
+    /// First channel, clients are spread over --channels consecutive channels from here
+    #[arg(long, default_value_t = 1234)]
+    channel: u64,
+    #[arg(long, default_value_t = 1)]
+    channels: u64,
+    /// Seconds between each client's embeds
+    #[arg(long, default_value_t = 10.0)]
+    embed_interval: f64,
+    /// Size of the snapshot attached to each embed in bytes, 0 for none
+    #[arg(long, default_value_t = 100_000)]
+    snapshot_size: usize,
+    /// Seconds between each client's file uploads, 0 for none
+    #[arg(long, default_value_t = 60.0)]
+    file_interval: f64,
+    /// Size of each uploaded file in bytes
+    #[arg(long, default_value_t = 1_000_000)]
+    file_size: usize,
+    /// Seconds between reports
+    #[arg(long, default_value_t = 5.0)]
+    report_interval: f64,
+}
+
+impl Args {
+    fn endpoint(&self) -> eyre::Result<Endpoint> {
+        if let Some(path) = &self.unix_socket {
+            return Ok(Endpoint::Unix(path.clone()));
+        }
+        if !self.tls {
+            return Ok(Endpoint::Tcp(self.address.clone()));
+        }
+        Endpoint::tls(self.address.clone(), self.tls_ca.as_deref(), None)
+    }
+
+    fn channel(&self, client: usize) -> u64 {
+        self.channel + client as u64 % self.channels
+    }
+}
+
+/// Measurements over a period of the run.
+#[derive(Default)]
+struct Stats {
+    acked: u64,
+    bytes: u64,
+    latencies: Vec<Duration>,
+    errors: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    fn record(&mut self, bytes: usize, latency: Duration, result: &eyre::Result<Vec<u64>>) {
+        match result {
+            Ok(_) => {
+                self.acked += 1;
+                self.bytes += bytes as u64;
+                self.latencies.push(latency);
+            }
+            Err(e) => {
+                let error = match e.downcast_ref::<Error>() {
+                    Some(error) => error.code().as_str_name().to_string(),
+                    None => e.to_string(),
+                };
+                *self.errors.entry(error).or_default() += 1;
+            }
+        }
+    }
+
+    fn report(&mut self, elapsed: Duration) -> String {
+        self.latencies.sort();
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let mut report = format!(
+            "acked {} ({:.1}/s, {:.2} MB/s), latency p50 {} p90 {} p99 {} max {}",
+            self.acked,
+            self.acked as f64 / secs,
+            self.bytes as f64 / secs / 1_000_000.0,
+            percentile(&self.latencies, 0.5),
+            percentile(&self.latencies, 0.9),
+            percentile(&self.latencies, 0.99),
+            percentile(&self.latencies, 1.0),
+        );
+        for (error, count) in &self.errors {
+            report.push_str(&format!(", {error} x{count}"));
+        }
+        report
+    }
+}
+
+fn percentile(sorted: &[Duration], quantile: f64) -> String {
+    if sorted.is_empty() {
+        return "-".to_string();
+    }
+    let index = ((sorted.len() as f64 * quantile).ceil() as usize).clamp(1, sorted.len()) - 1;
+    format!("{:.1}ms", sorted[index].as_secs_f64() * 1000.0)
+}
+
+/// Stats since the last report and for the whole run, and the connected clients.
+#[derive(Default)]
+struct Recorder {
+    interval: Mutex<Stats>,
+    total: Mutex<Stats>,
+    clients: Mutex<Vec<Arc<Client>>>,
+    connect_failures: AtomicU64,
+}
+
+impl Recorder {
+    fn record(&self, bytes: usize, latency: Duration, result: &eyre::Result<Vec<u64>>) {
+        self.interval.lock().unwrap().record(bytes, latency, result);
+        self.total.lock().unwrap().record(bytes, latency, result);
+    }
+
+    /// Connection counts are always for the whole run.
+    fn report(&self, stats: &mut Stats, elapsed: Duration) -> String {
+        let clients = self.clients.lock().unwrap();
+        let disconnects: u64 = clients.iter().map(|client| client.disconnects()).sum();
+        format!(
+            "clients {} (failed {}), {}, disconnects {disconnects}",
+            clients.len(),
+            self.connect_failures.load(Ordering::Relaxed),
+            stats.report(elapsed),
+        )
+    }
+}
+
+/// Data to slice snapshots and files from, random so that zipping doesn't shrink it.
+fn payload(size: usize) -> Vec<u8> {
+    let mut seed = 1u32;
+    (0..size)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect()
+}
+
+async fn simulate(
+    args: Arc<Args>,
+    endpoint: Endpoint,
+    id: usize,
+    recorder: Arc<Recorder>,
+    payload: Arc<Vec<u8>>,
+) {
+    let settings = Settings {
+        channel_id: args.channel(id),
+        ..Default::default()
+    };
+    let mut options = ClientOptions::new(endpoint, settings);
+    options.name = String::from("discordshim-load");
+    let client = match Client::connect(options).await {
+        Ok(client) => Arc::new(client),
+        Err(e) => {
+            warn!("Printer {id} failed to connect: {e}");
+            recorder.connect_failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    recorder.clients.lock().unwrap().push(client.clone());
+
+    let embed_interval = Duration::from_secs_f64(args.embed_interval);
+    let file_interval = Duration::from_secs_f64(args.file_interval);
+    let mut next_file = Instant::now() + file_interval;
+    let mut progress = 0;
+    loop {
+        let started = Instant::now();
+        progress = (progress + 1) % 100;
+        let embed = EmbedContent {
+            title: format!("Printer {id}"),
+            description: "Printing benchy.gcode".to_string(),
+            snapshot: (args.snapshot_size > 0).then(|| ProtoFile {
+                data: payload[..args.snapshot_size].to_vec(),
+                filename: "snapshot.jpg".to_string(),
+            }),
+            textfield: vec![TextField {
+                title: "Progress".to_string(),
+                text: format!("{progress}%"),
+                inline: true,
+            }],
+            ..Default::default()
+        };
+        let result = client.send_embed(embed).await;
+        let latency = started.elapsed();
+        recorder.record(args.snapshot_size, latency, &result);
+
+        if !file_interval.is_zero() && Instant::now() >= next_file {
+            next_file += file_interval;
+            let started = Instant::now();
+            let data = payload[..args.file_size].to_vec();
+            let result = client.send_file("benchy.gcode", data).await;
+            let latency = started.elapsed();
+            recorder.record(args.file_size, latency, &result);
+        }
+        task::sleep(embed_interval.saturating_sub(started.elapsed())).await;
+    }
+}
+
+/// Serve a shim on an ephemeral port against a fake discord that can see the load's channels.
+async fn start_fake(args: &Args) -> eyre::Result<String> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?.server,
+        None => Config::default().server,
+    };
+    let discord = Arc::new(FakeDiscord::default());
+    for channel in args.channel..args.channel + args.channels {
+        discord.add_channel(ChannelId::new(channel), Some(GuildId::new(1)));
+        config.trusted_channels.push(channel);
+    }
+    let server = Arc::new(Server::new(config));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?.to_string();
+    let fake = discord.clone();
+    task::spawn(async move { server.serve(listener, fake).await });
+    // Only the counts matter, so don't keep every message around.
+    task::spawn(async move {
+        loop {
+            task::sleep(Duration::from_secs(1)).await;
+            discord.clear();
+        }
+    });
+    Ok(address)
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    pretty_env_logger::init();
+    let mut args = Args::parse();
+    if args.channels == 0 || args.channel == 0 {
+        return Err(eyre!("--channel and --channels must be at least 1"));
+    }
+    if args.fake {
+        args.address = start_fake(&args).await?;
+        args.unix_socket = None;
+        args.tls = false;
+    }
+    let endpoint = args.endpoint()?;
+    let args = Arc::new(args);
+    let payload = Arc::new(payload(args.snapshot_size.max(args.file_size)));
+    let recorder = Arc::new(Recorder::default());
+
+    let started = Instant::now();
+    let ramp_up = Duration::from_secs_f64(args.ramp_up);
+    let end = started + ramp_up + Duration::from_secs_f64(args.duration);
+    for id in 0..args.clients {
+        let delay = ramp_up.mul_f64(id as f64 / args.clients as f64);
+        let (args, endpoint) = (args.clone(), endpoint.clone());
+        let (recorder, payload) = (recorder.clone(), payload.clone());
+        task::spawn(async move {
+            task::sleep(delay).await;
+            simulate(args, endpoint, id, recorder, payload).await;
+        });
+    }
+
+    let report_interval = Duration::from_secs_f64(args.report_interval);
+    let mut last_report = started;
+    while Instant::now() < end {
+        task::sleep(report_interval.min(end.saturating_duration_since(Instant::now()))).await;
+        let now = Instant::now();
+        let mut interval = std::mem::take(&mut *recorder.interval.lock().unwrap());
+        let report = recorder.report(&mut interval, now - last_report);
+        println!("[{:>5.0}s] {report}", (now - started).as_secs_f64());
+        last_report = now;
+    }
+
+    let mut total = std::mem::take(&mut *recorder.total.lock().unwrap());
+    let report = recorder.report(&mut total, started.elapsed());
+    println!("Total: {report}");
+    Ok(())
+}
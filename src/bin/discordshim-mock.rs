@@ -0,0 +1,704 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    fs,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_std::io::{BufReadExt, BufReader, stdin};
+use clap::Parser;
+use color_eyre::{eyre, eyre::eyre};
+use discordshim::{
+    config::Config,
+    discord::{Discord, MessageEdit, OutgoingMessage},
+    fake::{FakeDiscord, FakeMessage},
+    messages::{MessageInfo, ProtoFile},
+    server::Server,
+};
+use futures::StreamExt;
+use log::error;
+use serde_json::{Value, json};
+use serenity::{
+    all::{ActivityData, CreateAttachment, CreateCommand, CreateInteractionResponse, OnlineStatus},
+    async_trait,
+    model::id::{ChannelId, GuildId, InteractionId, MessageId, UserId},
+};
+
+const GUILD: GuildId = GuildId::new(1);
+const DEVELOPER: UserId = UserId::new(2);
+
+/// Run a shim without discord, for developing clients offline.
+///
+/// Clients connect exactly as to a real shim. Everything they post is printed, logged to
+/// events.jsonl in the output directory and its attachments saved. Lines typed on stdin are sent
+/// to the clients as commands, type :help for the other options.
+#[derive(Parser)]
+struct Args {
+    /// TOML config file, only the [server] section is used
+    #[arg(short, long, env = "DISCORDSHIM_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen for clients on
+    #[arg(long, env = "DISCORDSHIM_BIND")]
+    bind: Option<String>,
+    /// Channel clients can bind to without pairing
+    #[arg(long, default_value_t = 1234)]
+    channel: u64,
+    /// Directory for events.jsonl and attachments, cleared on start if it holds an earlier run
+    #[arg(long, default_value = "discordshim-mock")]
+    out: PathBuf,
+    /// Also keep an index.html preview of the channels up to date
+    #[arg(long)]
+    html: bool,
+}
+
+/// Remove the output of an earlier run. Refuses to touch a directory the mock didn't write, in
+/// case --out points somewhere like the home directory.
+fn clear_out(out: &Path) -> eyre::Result<()> {
+    if !out.exists() || fs::read_dir(out)?.next().is_none() {
+        return Ok(());
+    }
+    if !out.join("events.jsonl").is_file() {
+        return Err(eyre!("it has no events.jsonl from an earlier run"));
+    }
+    for file in ["events.jsonl", "index.html"] {
+        let path = out.join(file);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    let attachments = out.join("attachments");
+    if attachments.exists() {
+        fs::remove_dir_all(attachments)?;
+    }
+    Ok(())
+}
+
+/// A `FakeDiscord` that renders everything the shim does.
+struct MockDiscord {
+    fake: FakeDiscord,
+    out: PathBuf,
+    html: bool,
+    events: Mutex<File>,
+    channels: Mutex<BTreeSet<ChannelId>>,
+}
+
+impl MockDiscord {
+    fn new(out: PathBuf, html: bool) -> eyre::Result<MockDiscord> {
+        clear_out(&out).map_err(|e| eyre!("Failed to clear {}: {e}", out.display()))?;
+        fs::create_dir_all(&out)?;
+        let events = File::create(out.join("events.jsonl"))?;
+        let mock = MockDiscord {
+            fake: FakeDiscord::default(),
+            out,
+            html,
+            events: Mutex::new(events),
+            channels: Mutex::new(BTreeSet::new()),
+        };
+        mock.write_html();
+        Ok(mock)
+    }
+
+    fn add_channel(&self, channel: ChannelId) {
+        self.fake.add_channel(channel, Some(GUILD));
+        self.channels.lock().unwrap().insert(channel);
+    }
+
+    fn message(&self, channel: ChannelId, id: MessageId) -> Option<FakeMessage> {
+        let messages = self.fake.messages(channel);
+        messages.into_iter().find(|message| message.id == id)
+    }
+
+    /// Print an event, append it to events.jsonl and refresh the preview. Failing to write the
+    /// output doesn't fail the shim's request.
+    fn record(&self, event: Value) {
+        print!("{}", describe(&event));
+        let line = format!("{event}\n");
+        if let Err(e) = self.events.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Failed to write event: {e}");
+        }
+        self.write_html();
+    }
+
+    fn record_message(&self, kind: &str, message: &FakeMessage) {
+        let attachments = &message.message.attachments;
+        if let Err(e) = self.save_attachments(message.id, attachments) {
+            error!("Failed to save attachments of {}: {e}", message.id);
+        }
+        let mut event = message_json(message);
+        event["event"] = json!(kind);
+        self.record(event);
+    }
+
+    fn save_attachments(
+        &self,
+        id: MessageId,
+        attachments: &[CreateAttachment],
+    ) -> eyre::Result<()> {
+        for attachment in attachments {
+            let path = self.out.join(attachment_path(id, &attachment.filename));
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, &attachment.data)?;
+        }
+        Ok(())
+    }
+
+    fn write_html(&self) {
+        if !self.html {
+            return;
+        }
+        let channels = self.channels.lock().unwrap().clone();
+        let messages = channels
+            .into_iter()
+            .map(|channel| (channel, self.fake.messages(channel)))
+            .collect::<Vec<_>>();
+        let presence = self
+            .fake
+            .presences()
+            .pop()
+            .and_then(|(activity, _)| activity);
+        let html = render_html(&messages, presence.as_ref().map(|p| p.name.as_str()));
+        if let Err(e) = fs::write(self.out.join("index.html"), html) {
+            error!("Failed to write index.html: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl Discord for MockDiscord {
+    fn current_user(&self) -> UserId {
+        self.fake.current_user()
+    }
+
+    fn set_presence(&self, activity: Option<ActivityData>, status: OnlineStatus) {
+        let name = activity.as_ref().map(|activity| activity.name.clone());
+        self.fake.set_presence(activity, status);
+        self.record(json!({"event": "presence", "presence": name, "status": status}));
+    }
+
+    async fn send_message(
+        &self,
+        channel: ChannelId,
+        message: OutgoingMessage,
+    ) -> eyre::Result<MessageId> {
+        let id = self.fake.send_message(channel, message).await?;
+        if let Some(message) = self.message(channel, id) {
+            self.record_message("message", &message);
+        }
+        Ok(id)
+    }
+
+    async fn edit_message(
+        &self,
+        channel: ChannelId,
+        message_id: MessageId,
+        edit: MessageEdit,
+    ) -> eyre::Result<MessageId> {
+        let id = self.fake.edit_message(channel, message_id, edit).await?;
+        if let Some(message) = self.message(channel, id) {
+            self.record_message("edit", &message);
+        }
+        Ok(id)
+    }
+
+    async fn message_author(
+        &self,
+        channel: ChannelId,
+        message_id: MessageId,
+    ) -> eyre::Result<UserId> {
+        self.fake.message_author(channel, message_id).await
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: MessageId) -> eyre::Result<()> {
+        self.fake.delete_message(channel, message_id).await?;
+        self.record(json!({"event": "delete", "id": message_id.get(), "channel": channel.get()}));
+        Ok(())
+    }
+
+    async fn channel_guild(&self, channel: ChannelId) -> eyre::Result<Option<GuildId>> {
+        self.fake.channel_guild(channel).await
+    }
+
+    async fn set_guild_commands(
+        &self,
+        guild: GuildId,
+        commands: Vec<CreateCommand>,
+    ) -> eyre::Result<()> {
+        let event = json!({"event": "commands", "guild": guild.get(), "commands": commands});
+        self.fake.set_guild_commands(guild, commands).await?;
+        self.record(event);
+        Ok(())
+    }
+
+    async fn respond_to_interaction(
+        &self,
+        interaction: InteractionId,
+        token: &str,
+        response: CreateInteractionResponse,
+    ) -> eyre::Result<()> {
+        let event = json!({
+            "event": "interaction_response",
+            "interaction": interaction.get(),
+            "response": response,
+        });
+        self.fake
+            .respond_to_interaction(interaction, token, response)
+            .await?;
+        self.record(event);
+        Ok(())
+    }
+
+    async fn send_followup(
+        &self,
+        token: &str,
+        message: OutgoingMessage,
+        ephemeral: bool,
+    ) -> eyre::Result<MessageId> {
+        let attachments = message.attachments.clone();
+        let event = json!({
+            "event": "followup",
+            "content": message.content,
+            "embed": message.embed,
+            "components": message.components,
+            "ephemeral": ephemeral,
+        });
+        let id = self.fake.send_followup(token, message, ephemeral).await?;
+        if let Err(e) = self.save_attachments(id, &attachments) {
+            error!("Failed to save attachments of {id}: {e}");
+        }
+        let mut event = event;
+        event["id"] = json!(id.get());
+        event["attachments"] = json!(attachment_paths(id, &attachments));
+        self.record(event);
+        Ok(id)
+    }
+}
+
+/// Where an attachment is saved, relative to the output directory. Only the file name is kept, so
+/// a client can't write outside it.
+fn attachment_path(id: MessageId, filename: &str) -> PathBuf {
+    let name = Path::new(filename)
+        .file_name()
+        .map_or_else(|| "attachment".into(), |name| name.to_os_string());
+    Path::new("attachments").join(id.to_string()).join(name)
+}
+
+fn attachment_paths(id: MessageId, attachments: &[CreateAttachment]) -> Vec<String> {
+    attachments
+        .iter()
+        .map(|attachment| {
+            attachment_path(id, &attachment.filename)
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect()
+}
+
+fn message_json(message: &FakeMessage) -> Value {
+    json!({
+        "id": message.id.get(),
+        "channel": message.channel.get(),
+        "author": message.author.get(),
+        "content": message.message.content,
+        "embed": message.message.embed,
+        "components": message.message.components,
+        "attachments": attachment_paths(message.id, &message.message.attachments),
+        "reply_to": message.message.reply_to.map(MessageId::get),
+        "edits": message.edits,
+    })
+}
+
+fn text<'a>(value: &'a Value, pointer: &str) -> &'a str {
+    value.pointer(pointer).and_then(Value::as_str).unwrap_or("")
+}
+
+/// Buttons and select menus of a message, by label or placeholder.
+fn component_labels(event: &Value) -> Vec<&str> {
+    let rows = event["components"].as_array().into_iter().flatten();
+    let components = rows.flat_map(|row| row["components"].as_array().into_iter().flatten());
+    components
+        .map(|component| match text(component, "/label") {
+            "" => text(component, "/placeholder"),
+            label => label,
+        })
+        .collect()
+}
+
+/// A few lines describing an event for the terminal.
+fn describe(event: &Value) -> String {
+    let kind = text(event, "/event");
+    let mut out = match kind {
+        "message" | "edit" => format!("#{} {kind} {}", event["channel"], event["id"]),
+        "delete" => return format!("#{} delete {}\n", event["channel"], event["id"]),
+        "presence" => return format!("presence {}\n", event["presence"]),
+        "commands" => {
+            let commands = event["commands"].as_array().into_iter().flatten();
+            let names = commands
+                .map(|command| format!("/{}", text(command, "/name")))
+                .collect::<Vec<_>>();
+            return format!("commands {}\n", names.join(" "));
+        }
+        "interaction_response" => {
+            return format!("interaction {} acknowledged\n", event["interaction"]);
+        }
+        "followup" => format!("followup {}", event["id"]),
+        _ => return format!("{event}\n"),
+    };
+    if let Some(reply_to) = event["reply_to"].as_u64() {
+        let _ = write!(out, " (reply to {reply_to})");
+    }
+    if event["ephemeral"].as_bool() == Some(true) {
+        out.push_str(" (ephemeral)");
+    }
+    out.push('\n');
+
+    let mut lines = vec![text(event, "/content").to_string()];
+    let embed = &event["embed"];
+    lines.push(text(embed, "/author/name").to_string());
+    lines.push(text(embed, "/title").to_string());
+    lines.push(text(embed, "/description").to_string());
+    for field in embed["fields"].as_array().into_iter().flatten() {
+        lines.push(format!(
+            "{}: {}",
+            text(field, "/name"),
+            text(field, "/value")
+        ));
+    }
+    lines.push(text(embed, "/footer/text").to_string());
+    let labels = component_labels(event);
+    if !labels.is_empty() {
+        lines.push(
+            labels
+                .iter()
+                .map(|label| format!("[{label}]"))
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+    }
+    for attachment in event["attachments"].as_array().into_iter().flatten() {
+        lines.push(format!("attachment {}", attachment.as_str().unwrap_or("")));
+    }
+    // Empty embed descriptions are sent as a zero width space.
+    let blank = |line: &&String| line.trim_matches('\u{200b}').trim().is_empty();
+    for line in lines.iter().filter(|line| !blank(line)) {
+        for line in line.lines() {
+            let _ = writeln!(out, "  {line}");
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Point `attachment://` URLs at the saved attachments.
+fn image_url(url: &str, id: &Value) -> String {
+    match url.strip_prefix("attachment://") {
+        Some(name) => format!("attachments/{id}/{name}"),
+        None => url.to_string(),
+    }
+}
+
+fn render_embed(html: &mut String, message: &Value) {
+    let embed = &message["embed"];
+    if !embed.is_object() {
+        return;
+    }
+    let color = embed["color"].as_u64().unwrap_or(0x202225);
+    let _ = write!(
+        html,
+        r#"<div class="embed" style="border-color: #{color:06x}">"#
+    );
+    let author = text(embed, "/author/name");
+    if !author.is_empty() {
+        let _ = write!(html, r#"<div class="author">{}</div>"#, escape(author));
+    }
+    let thumbnail = text(embed, "/thumbnail/url");
+    if !thumbnail.is_empty() {
+        let src = escape(&image_url(thumbnail, &message["id"]));
+        let _ = write!(html, r#"<img class="thumbnail" src="{src}">"#);
+    }
+    let title = escape(text(embed, "/title"));
+    match text(embed, "/url") {
+        "" => {
+            let _ = write!(html, r#"<div class="title">{title}</div>"#);
+        }
+        url => {
+            let _ = write!(
+                html,
+                r#"<a class="title" href="{}">{title}</a>"#,
+                escape(url)
+            );
+        }
+    }
+    let _ = write!(
+        html,
+        r#"<div class="description">{}</div>"#,
+        escape(text(embed, "/description"))
+    );
+    for field in embed["fields"].as_array().into_iter().flatten() {
+        let class = if field["inline"].as_bool() == Some(true) {
+            "field inline"
+        } else {
+            "field"
+        };
+        let _ = write!(
+            html,
+            r#"<div class="{class}"><b>{}</b><div>{}</div></div>"#,
+            escape(text(field, "/name")),
+            escape(text(field, "/value")),
+        );
+    }
+    let image = text(embed, "/image/url");
+    if !image.is_empty() {
+        let src = escape(&image_url(image, &message["id"]));
+        let _ = write!(html, r#"<img class="image" src="{src}">"#);
+    }
+    let footer = text(embed, "/footer/text");
+    if !footer.is_empty() {
+        let _ = write!(html, r#"<div class="footer">{}</div>"#, escape(footer));
+    }
+    html.push_str("</div>");
+}
+
+const STYLE: &str = "body { font-family: sans-serif; background: #313338; color: #dbdee1; margin: 2em; }
+.message { margin: 1em 0; } .meta { color: #949ba4; font-size: 0.8em; }
+.embed { border-left: 4px solid; background: #2b2d31; padding: 0.5em 1em; margin: 0.3em 0; max-width: 520px; overflow: auto; }
+.title { font-weight: bold; color: #fff; display: block; } a { color: #00a8fc; }
+.description, .content { white-space: pre-wrap; } .field { margin-top: 0.5em; } .inline { display: inline-block; margin-right: 2em; }
+.image { max-width: 100%; margin-top: 0.5em; } .thumbnail { float: right; max-width: 80px; }
+.footer { font-size: 0.8em; margin-top: 0.5em; } .button { display: inline-block; background: #4e5058; padding: 0.3em 1em; margin: 0.2em; border-radius: 3px; }";
+
+fn render_html(channels: &[(ChannelId, Vec<FakeMessage>)], presence: Option<&str>) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>discordshim-mock</title><style>{STYLE}</style></head><body>"
+    );
+    if let Some(presence) = presence {
+        let _ = write!(html, "<p>Playing <b>{}</b></p>", escape(presence));
+    }
+    for (channel, messages) in channels {
+        let _ = write!(html, "<h2>#{channel}</h2>");
+        for message in messages {
+            let message = message_json(message);
+            let _ = write!(
+                html,
+                r#"<div class="message"><div class="meta">{} from {}{}</div>"#,
+                message["id"],
+                message["author"],
+                match message["edits"].as_u64() {
+                    Some(0) | None => String::new(),
+                    Some(edits) => format!(", edited {edits} times"),
+                },
+            );
+            let _ = write!(
+                html,
+                r#"<div class="content">{}</div>"#,
+                escape(text(&message, "/content"))
+            );
+            render_embed(&mut html, &message);
+            for label in component_labels(&message) {
+                let _ = write!(html, r#"<span class="button">{}</span>"#, escape(label));
+            }
+            for attachment in message["attachments"].as_array().into_iter().flatten() {
+                let path = escape(attachment.as_str().unwrap_or(""));
+                let _ = write!(html, r#"<div><a href="{path}">{path}</a></div>"#);
+            }
+            html.push_str("</div>");
+        }
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+const HELP: &str =
+    "Lines are sent to clients as commands from the developer, in the current channel.
+  :file <path> [text]  send a file, in a message with the text, e.g. a client's prefix
+  :channel <id>        switch to another channel
+  :pair                print a pairing code for the current channel
+  :help                show this help";
+
+/// Read commands from stdin until it is closed.
+async fn inject(server: Arc<Server>, mock: Arc<MockDiscord>, mut channel: ChannelId) {
+    println!("{HELP}");
+    let mut lines = BufReader::new(stdin()).lines();
+    while let Some(Ok(line)) = lines.next().await {
+        let (directive, argument) = line.split_once(' ').unwrap_or((&line, ""));
+        let result = match directive {
+            ":help" => {
+                println!("{HELP}");
+                Ok(())
+            }
+            ":channel" => match argument.trim().parse() {
+                Ok(id) if id != 0 => {
+                    channel = ChannelId::new(id);
+                    mock.add_channel(channel);
+                    Ok(())
+                }
+                _ => Err(eyre!("Usage: :channel <id>")),
+            },
+            ":pair" => {
+                let code = server.create_pairing_code(channel).await;
+                println!("Pairing code for #{channel}: {code}");
+                Ok(())
+            }
+            ":file" => {
+                let argument = argument.trim();
+                let (path, text) = argument.split_once(' ').unwrap_or((argument, ""));
+                send_file(&server, &mock, channel, Path::new(path), text.trim_start()).await
+            }
+            _ => {
+                let id = mock.fake.add_message(channel, DEVELOPER, &line);
+                let bot = mock.current_user();
+                server
+                    .send_command(channel, DEVELOPER, bot, line.clone(), info(channel, id))
+                    .await
+            }
+        };
+        if let Err(e) = result {
+            println!("{e}");
+        }
+    }
+}
+
+async fn send_file(
+    server: &Server,
+    mock: &MockDiscord,
+    channel: ChannelId,
+    path: &Path,
+    content: &str,
+) -> eyre::Result<()> {
+    let data = fs::read(path).map_err(|e| eyre!("Failed to read {}: {e}", path.display()))?;
+    let filename = path
+        .file_name()
+        .ok_or_else(|| eyre!("Usage: :file <path> [text]"))?
+        .to_string_lossy()
+        .into_owned();
+    let id = mock.fake.add_message(channel, DEVELOPER, content);
+    let bot = mock.current_user();
+    let file = ProtoFile { data, filename };
+    server
+        .send_file(channel, DEVELOPER, bot, content, file, info(channel, id))
+        .await
+}
+
+fn info(channel: ChannelId, message: MessageId) -> MessageInfo {
+    MessageInfo {
+        message_id: message.get(),
+        channel_id: channel.get(),
+        guild_id: GUILD.get(),
+        username: "developer".to_string(),
+        display_name: "Developer".to_string(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs() as i64),
+        ..Default::default()
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    pretty_env_logger::init_timed();
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?.server,
+        None => Config::default().server,
+    };
+    if let Some(bind) = args.bind {
+        config.bind = bind;
+    }
+    if args.channel == 0 {
+        return Err(eyre!("--channel must be a channel ID"));
+    }
+    config.trusted_channels.push(args.channel);
+    let channel = ChannelId::new(args.channel);
+
+    let mock = Arc::new(MockDiscord::new(args.out, args.html)?);
+    mock.add_channel(channel);
+    let server = Arc::new(Server::new(config));
+    println!("Clients can bind to #{channel} without pairing");
+
+    let discord: Arc<dyn Discord> = mock.clone();
+    futures::join!(
+        server.clone().run(discord),
+        inject(server.clone(), mock, channel)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use serde_json::json;
+    use serenity::model::id::MessageId;
+
+    use crate::{attachment_path, clear_out, describe, escape, image_url};
+
+    #[test]
+    fn test_clear_out() {
+        let out = env::temp_dir().join(format!("discordshim-mock-{}", uuid::Uuid::new_v4()));
+        assert!(clear_out(&out).is_ok());
+
+        // Someone else's files are left alone.
+        fs::create_dir_all(out.join("attachments/5")).unwrap();
+        fs::write(out.join("notes.txt"), "").unwrap();
+        assert!(clear_out(&out).is_err());
+        assert!(out.join("attachments/5").exists());
+
+        fs::write(out.join("events.jsonl"), "{}\n").unwrap();
+        fs::write(out.join("index.html"), "").unwrap();
+        clear_out(&out).unwrap();
+        assert!(!out.join("events.jsonl").exists());
+        assert!(!out.join("index.html").exists());
+        assert!(!out.join("attachments").exists());
+        assert!(out.join("notes.txt").exists());
+        fs::remove_dir_all(out).unwrap();
+    }
+
+    #[test]
+    fn test_attachment_path() {
+        let id = MessageId::new(5);
+        assert_eq!(
+            "attachments/5/snapshot.jpg",
+            attachment_path(id, "snapshot.jpg").to_str().unwrap()
+        );
+        assert_eq!(
+            "attachments/5/passwd",
+            attachment_path(id, "../../etc/passwd").to_str().unwrap()
+        );
+        assert_eq!(
+            "attachments/5/snapshot.jpg",
+            image_url("attachment://snapshot.jpg", &json!(5))
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let event = json!({
+            "event": "message",
+            "id": 5,
+            "channel": 1234,
+            "content": "",
+            "embed": {
+                "title": "Printing",
+                "fields": [{"name": "Progress", "value": "50%", "inline": false}],
+            },
+            "components": [{"type": 1, "components": [{"type": 2, "label": "Pause"}]}],
+            "attachments": ["attachments/5/snapshot.jpg"],
+            "reply_to": 3,
+        });
+        assert_eq!(
+            "#1234 message 5 (reply to 3)\n  Printing\n  Progress: 50%\n  [Pause]\n  attachment attachments/5/snapshot.jpg\n",
+            describe(&event)
+        );
+        assert_eq!("&lt;b&gt; &amp; &quot;", escape("<b> & \""));
+    }
+}
@@ -1,39 +1,123 @@
-use std::{env, sync::Arc};
+use std::{env, path::PathBuf, sync::Arc};
 
-use async_std::sync::RwLock;
+use clap::Parser;
 use color_eyre::{eyre, eyre::eyre};
-use discordshim::server::Server;
-use poise::{Framework, async_trait, serenity_prelude as serenity};
+use discordshim::{
+    config::{Config, TlsConfig},
+    messages::ProtoFile,
+    server::{Server, edited_message_info, message_info},
+};
+use log::error;
+use poise::{CreateReply, Framework, async_trait, serenity_prelude as serenity};
 use serenity::{
     Client,
-    all::{ChannelId, Context, EventHandler, GatewayIntents, Message, Ready},
+    all::{
+        ChannelId,
+        Context,
+        EventHandler,
+        GatewayIntents,
+        Interaction,
+        Message,
+        MessageUpdateEvent,
+        Ready,
+    },
 };
 use tokio::task;
 
-struct Data {} // User data, which is stored and accessible in all command invocations
+// User data, which is stored and accessible in all command invocations
+struct Data {
+    server: Arc<Server>,
+}
 type Error = Box<dyn std::error::Error + Send + Sync>;
+type PoiseContext<'a> = poise::Context<'a, Data, Error>;
+
+/// Relay messages between discord and DiscordShim clients.
+///
+/// Options are read from the config file, then overridden by environment variables and flags.
+#[derive(Parser)]
+struct Args {
+    /// TOML config file
+    #[arg(short, long, env = "DISCORDSHIM_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen for clients on
+    #[arg(long, env = "DISCORDSHIM_BIND")]
+    bind: Option<String>,
+    /// Discord bot token
+    #[arg(long, env = "DISCORD_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// File to read the discord bot token from, e.g. a docker secret
+    #[arg(long, env = "DISCORD_TOKEN_FILE")]
+    token_file: Option<PathBuf>,
+    /// Channel used by the healthcheck binary
+    #[arg(long, env = "HEALTH_CHECK_CHANNEL_ID")]
+    health_check_channel: Option<u64>,
+    /// Channel clients can bind to without pairing, added to trusted_channels
+    #[arg(
+        long = "trusted-channel",
+        env = "DISCORDSHIM_TRUSTED_CHANNELS",
+        value_delimiter = ','
+    )]
+    trusted_channels: Vec<u64>,
+    /// PEM certificate chain for the TLS listener
+    #[arg(long, env = "DISCORDSHIM_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for the TLS listener
+    #[arg(long, env = "DISCORDSHIM_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Run as the shared cloud bot, also set by CLOUD_SERVER having any value
+    #[arg(long)]
+    cloud: bool,
+}
+
+impl Args {
+    fn config(self) -> eyre::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(bind) = self.bind {
+            config.server.bind = bind;
+        }
+        // A token given here replaces the one from the file, whichever way it was set there.
+        if self.token.is_some() || self.token_file.is_some() {
+            config.discord.token = self.token;
+            config.discord.token_file = self.token_file;
+        }
+        if self.health_check_channel.is_some() {
+            config.discord.health_check_channel = self.health_check_channel;
+        }
+        config.server.trusted_channels.extend(self.trusted_channels);
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            let tls = config.server.tls.get_or_insert_with(TlsConfig::default);
+            tls.cert = cert;
+            tls.key = key;
+        }
+        // Setting CLOUD_SERVER at all has always meant the cloud bot, even to "false".
+        config.server.cloud |= self.cloud || env::var_os("CLOUD_SERVER").is_some();
+        config.validate()?;
+        Ok(config)
+    }
+}
 
 struct Handler {
-    healthcheckchannel: ChannelId,
-    server: Arc<RwLock<Server>>,
+    healthcheckchannel: Option<ChannelId>,
+    server: Arc<Server>,
 }
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, new_message: Message) {
         // Check for statistics messages
-        if new_message.channel_id == self.healthcheckchannel && new_message.content == "/stats" {
-            self.server
-                .read()
-                .await
-                .send_stats(new_message.channel_id, ctx.clone())
-                .await;
+        if Some(new_message.channel_id) == self.healthcheckchannel
+            && new_message.content == "/stats"
+        {
+            self.server.send_stats(new_message.channel_id, &ctx).await;
         }
 
         // Check for health check message.
         if new_message.author == **ctx.cache.current_user() {
             // Message is from ourselves.
-            if new_message.channel_id == self.healthcheckchannel {
+            if Some(new_message.channel_id) == self.healthcheckchannel {
                 if new_message.embeds.len() != 1 {
                     return;
                 }
@@ -44,9 +128,13 @@ impl EventHandler for Handler {
                 let flag = embed1.title.as_ref().unwrap().clone();
                 let _ = self
                     .server
-                    .read()
-                    .await
-                    .send_command(new_message.channel_id, new_message.author.id, flag)
+                    .send_command(
+                        new_message.channel_id,
+                        new_message.author.id,
+                        new_message.author.id,
+                        flag,
+                        message_info(&new_message),
+                    )
                     .await;
                 return;
             }
@@ -58,40 +146,113 @@ impl EventHandler for Handler {
             return;
         }
         // Process all other messages as normal.
+        let bot = ctx.cache.current_user().id;
+        let info = message_info(&new_message);
         let _ = self
             .server
-            .read()
-            .await
             .send_command(
                 new_message.channel_id,
                 new_message.author.id,
-                new_message.content,
+                bot,
+                new_message.content.clone(),
+                info.clone(),
             )
             .await;
         for attachment in new_message.attachments {
-            let filedata = attachment.download().await.unwrap();
+            let filedata = match attachment.download().await {
+                Ok(filedata) => filedata,
+                Err(e) => {
+                    error!("Failed to download attachment {}: {e}", attachment.filename);
+                    continue;
+                }
+            };
+            let file = ProtoFile {
+                data: filedata,
+                filename: attachment.filename,
+            };
             let _ = self
                 .server
-                .read()
-                .await
                 .send_file(
                     new_message.channel_id,
                     new_message.author.id,
-                    attachment.filename,
-                    filedata,
+                    bot,
+                    &new_message.content,
+                    file,
+                    info.clone(),
                 )
                 .await;
         }
     }
 
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        // Only edits that change the content of a guild message are forwarded.
+        let (Some(content), Some(author), Some(_)) =
+            (&event.content, &event.author, event.guild_id)
+        else {
+            return;
+        };
+        let bot = ctx.cache.current_user().id;
+        if author.id == bot {
+            return;
+        }
+        let Some(info) = edited_message_info(&event) else {
+            return;
+        };
+
+        let _ = self
+            .server
+            .send_command(event.channel_id, author.id, bot, content.clone(), info)
+            .await;
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::Component(component) => {
+                self.server.send_interaction(&ctx, component).await;
+            }
+            // Global commands, like /pair, are handled by the framework.
+            Interaction::Command(command) if command.data.guild_id.is_some() => {
+                self.server.send_slash_command(&ctx, command).await;
+            }
+            _ => {}
+        }
+    }
+
     async fn ready(&self, _ctx: Context, _ready: Ready) {
         let ctx = Arc::new(_ctx);
         task::spawn(run_server(ctx, self.server.clone()));
     }
 }
 
-async fn run_server(ctx: Arc<Context>, server: Arc<RwLock<Server>>) {
-    server.read().await.run(ctx).await;
+/// Get a one-time code to pair a DiscordShim client with this channel
+#[poise::command(
+    slash_command,
+    guild_only,
+    default_member_permissions = "MANAGE_CHANNELS"
+)]
+async fn pair(ctx: PoiseContext<'_>) -> Result<(), Error> {
+    let code = ctx
+        .data()
+        .server
+        .create_pairing_code(ctx.channel_id())
+        .await;
+    let reply = CreateReply::default()
+        .content(format!(
+            "Pairing code for this channel: `{code}`\nIt can be used once, within 10 minutes."
+        ))
+        .ephemeral(true);
+    ctx.send(reply).await?;
+    Ok(())
+}
+
+async fn run_server(ctx: Arc<Context>, server: Arc<Server>) {
+    server.run(ctx).await;
 }
 
 #[tokio::main]
@@ -99,34 +260,48 @@ async fn main() {
     pretty_env_logger::init_timed();
     console_subscriber::init();
 
-    serve().await.unwrap();
+    let config = match Args::parse().config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+    serve(config).await.unwrap();
 }
 
-async fn serve() -> eyre::Result<()> {
+async fn serve(mut config: Config) -> eyre::Result<()> {
+    let token = config.discord.token()?;
+    let healthcheckchannel = config.discord.health_check_channel.map(ChannelId::new);
+
+    // The health check channel is trusted, so the healthcheck binary doesn't need to pair.
+    if let Some(channel) = config.discord.health_check_channel {
+        config.server.trusted_channels.push(channel);
+    }
+    let server = Arc::new(Server::new(config.server));
+
+    let framework_server = server.clone();
     let framework: Framework<Data, Error> = Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![],
+            commands: vec![pair()],
             ..Default::default()
         })
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
-                Ok(Data {})
+                Ok(Data {
+                    server: framework_server,
+                })
             })
         })
         .build();
 
-    let channelid: u64 = env::var("HEALTH_CHECK_CHANNEL_ID")
-        .expect("channel id")
-        .parse()?;
-
     let handler = Handler {
-        healthcheckchannel: ChannelId::from(channelid),
-        server: Arc::new(RwLock::new(Server::new())),
+        healthcheckchannel,
+        server,
     };
 
-    // Login with a bot token from the environment
-    let token = env::var("DISCORD_TOKEN").expect("token");
+    // Login with the bot token from the config
     let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
     let mut client: Client = Client::builder(token, intents)
         .event_handler(handler)
@@ -1,63 +1,69 @@
-use std::env;
+use std::{env, path::PathBuf};
 
-use async_std::{io::ReadExt, net::TcpStream};
-use byteorder::{ByteOrder, LittleEndian};
 use color_eyre::{eyre, eyre::eyre};
-use discordshim::messages::{
-    EmbedContent,
-    Request,
-    Response,
-    Settings,
-    request::Message::Command,
-    response::Field,
+use discordshim::{
+    client::{Client, ClientOptions, Endpoint},
+    messages::{EmbedContent, Request, Settings, request::Message::Command},
 };
-use futures::AsyncWriteExt;
-use prost::Message;
+use futures::StreamExt;
 
 #[tokio::main]
 pub async fn main() -> eyre::Result<()> {
-    let mut client = TcpStream::connect("127.0.0.1:23416").await.unwrap();
+    let address =
+        env::var("HEALTH_CHECK_ADDRESS").unwrap_or_else(|_| String::from("127.0.0.1:23416"));
     let channel_id: u64 = env::var("HEALTH_CHECK_CHANNEL_ID")
         .expect("channel id")
         .parse()?;
-    let flag = uuid::Uuid::new_v4().to_string();
-
-    // Send settings
-    {
-        let response = Response {
-            field: Some(Field::Settings(Settings {
-                channel_id,
-                ..Default::default()
-            })),
-        };
 
-        let bytes = response.encode_to_vec();
-        send_data(&mut client, bytes).await?;
-    }
-    // Send flag
-    {
-        let response = Response {
-            field: Some(Field::Embed(EmbedContent {
-                title: flag.clone(),
-                ..Default::default()
-            })),
+    let endpoint = if let Ok(path) = env::var("HEALTH_CHECK_UNIX_SOCKET") {
+        // Same-host deployments can check the Unix socket instead.
+        Endpoint::Unix(PathBuf::from(path))
+    } else if env::var("HEALTH_CHECK_TLS").is_ok() {
+        // The CA defaults to the public web roots, the certificate is only needed if the shim
+        // requires one.
+        let ca = env::var("HEALTH_CHECK_TLS_CA").ok().map(PathBuf::from);
+        let cert = env::var("HEALTH_CHECK_TLS_CERT").ok().map(PathBuf::from);
+        let key = env::var("HEALTH_CHECK_TLS_KEY").ok().map(PathBuf::from);
+        let identity = match (&cert, &key) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
         };
+        Endpoint::tls(address, ca.as_deref(), identity)?
+    } else {
+        Endpoint::Tcp(address)
+    };
 
-        let bytes = response.encode_to_vec();
-        send_data(&mut client, bytes).await?;
-    }
+    let settings = Settings {
+        channel_id,
+        ..Default::default()
+    };
+    let mut options = ClientOptions::new(endpoint, settings);
+    options.name = String::from("healthcheck");
+    options.reconnect = None;
+    let client = Client::connect(options).await?;
+    check(&client).await
+}
 
-    // Read up to 5 responses
-    for _ in 0..5 {
-        let length_buf = &mut [0u8; 4];
-        client.read_exact(length_buf).await?;
-        let length = LittleEndian::read_u32(length_buf) as usize;
+async fn check(client: &Client) -> eyre::Result<()> {
+    let flag = uuid::Uuid::new_v4().to_string();
+    let mut requests = client.requests();
 
-        let mut buf = vec![0u8; length];
-        client.read_exact(&mut buf).await?;
+    // Send flag
+    let embed = EmbedContent {
+        title: flag.clone(),
+        ..Default::default()
+    };
+    client.send_embed(embed).await?;
 
-        let request = Request::decode(buf.as_slice())?;
-        if let Some(Command(command)) = request.message
+    // Read up to 5 requests
+    for _ in 0..5 {
+        let Some(request) = requests.next().await else {
+            break;
+        };
+        if let Request {
+            message: Some(Command(command)),
+            ..
+        } = request
             && command == flag
         {
             return Ok(()); // Success
@@ -65,13 +71,3 @@ pub async fn main() -> eyre::Result<()> {
     }
     Err(eyre!("Failed healthcheck"))
 }
-
-async fn send_data(tcpstream: &mut TcpStream, data: Vec<u8>) -> eyre::Result<()> {
-    let length = data.len() as u32;
-    let length_buf = &mut [0u8; 4];
-    LittleEndian::write_u32(length_buf, length);
-
-    tcpstream.write_all(length_buf).await?;
-    tcpstream.write_all(&data).await?;
-    Ok(())
-}
@@ -0,0 +1,512 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use async_std::{
+    channel::{Receiver, Sender, bounded, unbounded},
+    io,
+    io::{Read, Write},
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    task,
+};
+use color_eyre::{eyre, eyre::eyre};
+use futures::{
+    AsyncReadExt,
+    Stream,
+    future::{Either, select},
+    pin_mut,
+};
+use futures_rustls::TlsConnector;
+use log::{debug, info, warn};
+use prost::Message as _;
+use rustls_pki_types::ServerName;
+
+use crate::{
+    handshake::PROTOCOL_VERSION,
+    inbound::{ConnectionLimits, read_frame},
+    messages::{
+        EmbedContent,
+        Error,
+        Feature,
+        Hello,
+        Presence,
+        ProtoFile,
+        Request,
+        Response,
+        Settings,
+        request::Message,
+        response::Field,
+    },
+    outbound::write_frame,
+    tls::connector,
+    transport::BoxedConnection,
+};
+
+/// Where the shim is listening.
+#[derive(Clone)]
+pub enum Endpoint {
+    /// A plaintext TCP address, e.g. `127.0.0.1:23416`.
+    Tcp(String),
+    /// A TLS listener, the shim's certificate is checked against `name`.
+    Tls {
+        address: String,
+        name: ServerName<'static>,
+        connector: TlsConnector,
+    },
+    /// The path of the shim's Unix socket.
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// A TLS endpoint, the shim's certificate is checked against the host part of `address`. See
+    /// `tls::connector` for `ca` and `identity`.
+    pub fn tls(
+        address: String,
+        ca: Option<&Path>,
+        identity: Option<(&Path, &Path)>,
+    ) -> eyre::Result<Endpoint> {
+        let connector = connector(ca, identity)?;
+        let name = ServerName::try_from(host(&address).to_string())?;
+        Ok(Endpoint::Tls {
+            address,
+            name,
+            connector,
+        })
+    }
+
+    async fn connect(&self) -> eyre::Result<BoxedConnection> {
+        Ok(match self {
+            Endpoint::Tcp(address) => Box::new(connect_tcp(address).await?),
+            Endpoint::Tls {
+                address,
+                name,
+                connector,
+            } => {
+                let stream = connect_tcp(address).await?;
+                Box::new(connector.connect(name.clone(), stream).await?)
+            }
+            Endpoint::Unix(path) => Box::new(UnixStream::connect(path).await?),
+        })
+    }
+}
+
+// Frames are written as a length and a body, which Nagle's algorithm would hold back until the
+// shim acks the length.
+async fn connect_tcp(address: &str) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// The host of a `host:port` address, without the brackets around an IPv6 address.
+fn host(address: &str) -> &str {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    match host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        Some(ip) => ip,
+        None => host,
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => f.write_str(address),
+            Endpoint::Tls { address, .. } => write!(f, "tls:{address}"),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// How long to wait between reconnection attempts, doubling after each failure up to `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+pub struct ClientOptions {
+    pub endpoint: Endpoint,
+    /// Sent after every (re)connect, with the session token filled in by the client.
+    pub settings: Settings,
+    /// Name and version sent in the `Hello`, shown in the shim's logs and stats.
+    pub name: String,
+    pub version: String,
+    /// None to give up when the connection drops, instead of reconnecting.
+    pub reconnect: Option<Backoff>,
+    /// Have the shim keep the session and buffer requests while disconnected, so they are
+    /// replayed after reconnecting. Ignored without `reconnect`.
+    pub sessions: bool,
+}
+
+impl ClientOptions {
+    pub fn new(endpoint: Endpoint, settings: Settings) -> ClientOptions {
+        ClientOptions {
+            endpoint,
+            settings,
+            name: String::from("discordshim-client"),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            reconnect: Some(Backoff::default()),
+            sessions: true,
+        }
+    }
+
+    /// Features to announce in the `Hello`. A client that won't reconnect has no use for a
+    /// session, and would leave it detached on the shim for the grace period.
+    fn capabilities(&self) -> Vec<i32> {
+        let mut capabilities = vec![Feature::Acks.into()];
+        if self.sessions && self.reconnect.is_some() {
+            capabilities.push(Feature::Sessions.into());
+        }
+        capabilities
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code().as_str_name(), self.message)
+    }
+}
+
+/// Errors sent by the shim are returned as the `Error` message, so callers can downcast to it
+/// and check the code.
+impl std::error::Error for Error {}
+
+/// Write one `Response`, prefixed with its length.
+pub async fn write_response<W: Write + Unpin>(
+    stream: &mut W,
+    response: &Response,
+) -> eyre::Result<()> {
+    write_frame(stream, &response.encode_to_vec()).await
+}
+
+/// Read one length-prefixed `Request`.
+pub async fn read_request<R: Read + Unpin>(stream: &mut R) -> eyre::Result<Request> {
+    let limits = ConnectionLimits::default();
+    let frame = read_frame(stream, &limits).await?;
+    Ok(Request::decode(frame.as_slice())?)
+}
+
+type Waiters = Arc<Mutex<HashMap<u64, Sender<eyre::Result<Vec<u64>>>>>>;
+
+/// A connection to the shim that survives reconnects. Requests the shim had for the client while
+/// it was disconnected are replayed when the session is resumed.
+pub struct Client {
+    ids: Arc<AtomicU64>,
+    disconnects: Arc<AtomicU64>,
+    waiters: Waiters,
+    outbound: Sender<Response>,
+    requests: Receiver<Request>,
+}
+
+impl Client {
+    /// Connect and send the settings. Fails if the first connection can't be made or the shim
+    /// rejects the settings, later disconnects are handled according to `options.reconnect`.
+    pub async fn connect(options: ClientOptions) -> eyre::Result<Client> {
+        let ids = Arc::new(AtomicU64::new(0));
+        let disconnects = Arc::new(AtomicU64::new(0));
+        let waiters = Waiters::default();
+        let (outbound, outbound_receiver) = bounded(64);
+        let (inbound, requests) = unbounded();
+        let mut session = Session {
+            options,
+            ids: ids.clone(),
+            disconnects: disconnects.clone(),
+            waiters: waiters.clone(),
+            outbound: outbound_receiver,
+            inbound,
+            token: None,
+        };
+
+        let stream = session.open().await?;
+        task::spawn(session.run(stream));
+        Ok(Client {
+            ids,
+            disconnects,
+            waiters,
+            outbound,
+            requests,
+        })
+    }
+
+    /// Requests from the shim, e.g. commands and files posted in the channel. Requests are
+    /// buffered until read, each one is yielded to only one of the streams. The stream ends once
+    /// the client gives up reconnecting.
+    pub fn requests(&self) -> impl Stream<Item = Request> + Unpin + use<> {
+        self.requests.clone()
+    }
+
+    /// Number of times the connection dropped since connecting.
+    pub fn disconnects(&self) -> u64 {
+        self.disconnects.load(Ordering::Relaxed)
+    }
+
+    /// Send a `Response` and wait for the shim to handle it, returning the IDs of the discord
+    /// messages it posted. The correlation ID is set by the client. Fails with an `Error` if the
+    /// shim rejected it, or if the connection was lost before the shim replied.
+    pub async fn send(&self, mut response: Response) -> eyre::Result<Vec<u64>> {
+        response.id = self.ids.fetch_add(1, Ordering::Relaxed) + 1;
+        let (sender, receiver) = bounded(1);
+        self.waiters.lock().unwrap().insert(response.id, sender);
+        if self.outbound.send(response).await.is_err() {
+            return Err(eyre!("Client is closed"));
+        }
+        receiver
+            .recv()
+            .await
+            .unwrap_or_else(|_| Err(eyre!("Connection to the shim was lost")))
+    }
+
+    pub async fn send_embed(&self, embed: EmbedContent) -> eyre::Result<Vec<u64>> {
+        self.send_field(Field::Embed(embed)).await
+    }
+
+    /// Upload a file, the shim splits it across several messages if it is too large.
+    pub async fn send_file(
+        &self,
+        filename: impl Into<String>,
+        data: Vec<u8>,
+    ) -> eyre::Result<Vec<u64>> {
+        let file = ProtoFile {
+            data,
+            filename: filename.into(),
+        };
+        self.send_field(Field::File(file)).await
+    }
+
+    pub async fn set_presence(&self, presence: impl Into<String>) -> eyre::Result<()> {
+        let presence = Presence {
+            presence: presence.into(),
+        };
+        self.send_field(Field::Presence(presence)).await?;
+        Ok(())
+    }
+
+    async fn send_field(&self, field: Field) -> eyre::Result<Vec<u64>> {
+        let response = Response {
+            field: Some(field),
+            ..Default::default()
+        };
+        self.send(response).await
+    }
+}
+
+/// The connection task's state, kept across reconnects.
+struct Session {
+    options: ClientOptions,
+    ids: Arc<AtomicU64>,
+    disconnects: Arc<AtomicU64>,
+    waiters: Waiters,
+    outbound: Receiver<Response>,
+    inbound: Sender<Request>,
+    token: Option<String>,
+}
+
+impl Session {
+    fn next_id(&self) -> u64 {
+        self.ids.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Connect, say hello and send the settings, resuming the session if there is one.
+    async fn open(&mut self) -> eyre::Result<BoxedConnection> {
+        let mut stream = self.options.endpoint.connect().await?;
+
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: self.options.name.clone(),
+            client_version: self.options.version.clone(),
+            capabilities: self.options.capabilities(),
+        };
+        let hello_id = self.next_id();
+        self.request(&mut stream, hello_id, Field::Hello(hello))
+            .await?;
+
+        let settings = Settings {
+            session_token: self.token.clone().unwrap_or_default(),
+            ..self.options.settings.clone()
+        };
+        let settings_id = self.next_id();
+        self.request(&mut stream, settings_id, Field::Settings(settings))
+            .await?;
+        info!("Connected to {}", self.options.endpoint);
+        Ok(stream)
+    }
+
+    /// Send a `Response` during setup and wait for its reply. Shims that predate the handshake
+    /// ack the `Hello` instead of replying with a `ServerHello`.
+    async fn request(
+        &mut self,
+        stream: &mut BoxedConnection,
+        id: u64,
+        field: Field,
+    ) -> eyre::Result<()> {
+        let response = Response {
+            id,
+            field: Some(field),
+            ..Default::default()
+        };
+        write_response(stream, &response).await?;
+        // A resumed session's buffered requests arrive before the `Settings` are acked.
+        loop {
+            let request = read_request(stream).await?;
+            match &request.message {
+                Some(Message::Hello(hello)) => {
+                    debug!("Shim is version {}", hello.server_version);
+                    return Ok(());
+                }
+                Some(Message::Ack(ack)) if ack.id == id => return Ok(()),
+                Some(Message::Error(error)) if error.id == id => return Err(error.clone().into()),
+                _ => self.dispatch(request),
+            }
+        }
+    }
+
+    /// Route a request to the waiting `send`, or to the requests stream.
+    fn dispatch(&mut self, request: Request) {
+        let reply = match &request.message {
+            Some(Message::Session(session)) => {
+                self.token = Some(session.token.clone());
+                return;
+            }
+            Some(Message::Ack(ack)) => (ack.id, Ok(ack.message_ids.clone())),
+            Some(Message::Error(error)) if error.id != 0 => (error.id, Err(error.clone().into())),
+            _ => {
+                let _ = self.inbound.try_send(request);
+                return;
+            }
+        };
+        if let Some(waiter) = self.waiters.lock().unwrap().remove(&reply.0) {
+            let _ = waiter.try_send(reply.1);
+        }
+    }
+
+    async fn run(mut self, mut stream: BoxedConnection) {
+        loop {
+            match self.serve(stream).await {
+                Ok(()) => {
+                    debug!(
+                        "Client closed, disconnecting from {}",
+                        self.options.endpoint
+                    );
+                    return;
+                }
+                Err(e) => warn!("Lost connection to {}: {e}", self.options.endpoint),
+            }
+            self.disconnects.fetch_add(1, Ordering::Relaxed);
+            self.fail_pending();
+            stream = match self.reconnect().await {
+                Some(stream) => stream,
+                None => return,
+            };
+        }
+    }
+
+    /// Pass requests both ways until the connection drops, or the client is dropped.
+    async fn serve(&mut self, stream: BoxedConnection) -> eyre::Result<()> {
+        let (mut reader, mut writer) = stream.split();
+        let outbound = self.outbound.clone();
+        let write = async move {
+            while let Ok(response) = outbound.recv().await {
+                write_response(&mut writer, &response).await?;
+            }
+            eyre::Ok(())
+        };
+        let read = async {
+            loop {
+                let request = read_request(&mut reader).await?;
+                self.dispatch(request);
+            }
+        };
+        pin_mut!(write, read);
+        match select(write, read).await {
+            Either::Left((result, _)) => result,
+            Either::Right((result, _)) => result,
+        }
+    }
+
+    /// Fail the requests that were in flight, and drop the ones that weren't sent yet, since the
+    /// shim won't reply to them.
+    fn fail_pending(&mut self) {
+        while self.outbound.try_recv().is_ok() {}
+        self.waiters.lock().unwrap().clear();
+    }
+
+    /// Keep reconnecting until it works, returning None if the client shouldn't reconnect or the
+    /// shim rejected it.
+    async fn reconnect(&mut self) -> Option<BoxedConnection> {
+        let backoff = self.options.reconnect?;
+        let mut delay = backoff.initial;
+        loop {
+            if self.outbound.is_closed() {
+                return None;
+            }
+            task::sleep(delay).await;
+            match self.open().await {
+                Ok(stream) => return Some(stream),
+                Err(e) if e.downcast_ref::<Error>().is_some() => {
+                    warn!("{} rejected the client: {e}", self.options.endpoint);
+                    return None;
+                }
+                Err(e) => warn!("Failed to reconnect to {}: {e}", self.options.endpoint),
+            }
+            delay = (delay * 2).min(backoff.max);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls_pki_types::ServerName;
+
+    use crate::{
+        client::{ClientOptions, Endpoint, host},
+        messages::{Feature, Settings},
+    };
+
+    #[test]
+    fn test_host() {
+        assert_eq!("shim.example.com", host("shim.example.com:23416"));
+        assert_eq!("127.0.0.1", host("127.0.0.1:23416"));
+        assert_eq!("::1", host("[::1]:23416"));
+        assert_eq!("shim.example.com", host("shim.example.com"));
+
+        let name = ServerName::try_from(host("[::1]:23416").to_string()).unwrap();
+        assert!(matches!(name, ServerName::IpAddress(_)));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let endpoint = Endpoint::Tcp("127.0.0.1:23416".to_string());
+        let mut options = ClientOptions::new(endpoint, Settings::default());
+        let sessions: i32 = Feature::Sessions.into();
+        assert!(options.capabilities().contains(&sessions));
+
+        options.sessions = false;
+        assert!(!options.capabilities().contains(&sessions));
+
+        // One-shot clients don't leave a session behind.
+        options.sessions = true;
+        options.reconnect = None;
+        assert!(!options.capabilities().contains(&sessions));
+    }
+}
@@ -0,0 +1,202 @@
+use color_eyre::eyre;
+use serenity::all::{
+    CommandDataOption,
+    CommandDataOptionValue,
+    CommandOptionType,
+    CreateCommand,
+    CreateCommandOption,
+};
+
+use crate::{
+    error::ShimError,
+    messages::{
+        CommandArgument,
+        CommandDefinition,
+        CommandOption,
+        ErrorCode,
+        OptionType,
+        command_argument::Value,
+    },
+};
+
+pub const DISCORD_MAX_COMMANDS: usize = 100;
+pub const DISCORD_MAX_COMMAND_OPTIONS: usize = 25;
+pub const DISCORD_MAX_COMMAND_CHOICES: usize = 25;
+pub const DISCORD_MAX_COMMAND_NAME: usize = 32;
+pub const DISCORD_MAX_COMMAND_DESCRIPTION: usize = 100;
+
+/// Check names and descriptions against discord's rules. Discord rejects a guild's commands as a
+/// whole, so one bad definition would break them for every client in the guild.
+pub(crate) fn check_definitions(commands: &[CommandDefinition]) -> eyre::Result<()> {
+    for command in commands {
+        check_definition(&command.name, &command.description)?;
+        for option in &command.options {
+            check_definition(&option.name, &option.description)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_definition(name: &str, description: &str) -> eyre::Result<()> {
+    let length = name.chars().count();
+    let valid_name = (1..=DISCORD_MAX_COMMAND_NAME).contains(&length)
+        && name
+            .chars()
+            .all(|c| c == '-' || c == '_' || (c.is_alphanumeric() && !c.is_uppercase()));
+    if !valid_name {
+        return Err(ShimError::new(
+            ErrorCode::MalformedMessage,
+            format!(
+                "Command and option names must be 1 to {DISCORD_MAX_COMMAND_NAME} lowercase \
+                 letters, digits, - or _: {name:?}"
+            ),
+        )
+        .into());
+    }
+    let length = description.chars().count();
+    if !(1..=DISCORD_MAX_COMMAND_DESCRIPTION).contains(&length) {
+        return Err(ShimError::new(
+            ErrorCode::MalformedMessage,
+            format!(
+                "Description of {name} must be 1 to {DISCORD_MAX_COMMAND_DESCRIPTION} characters"
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+pub(crate) fn create_command(definition: &CommandDefinition) -> CreateCommand {
+    let options = definition
+        .options
+        .iter()
+        .take(DISCORD_MAX_COMMAND_OPTIONS)
+        .map(create_option)
+        .collect();
+    CreateCommand::new(&definition.name)
+        .description(&definition.description)
+        .set_options(options)
+}
+
+fn create_option(option: &CommandOption) -> CreateCommandOption {
+    let kind = match option.r#type() {
+        OptionType::String => CommandOptionType::String,
+        OptionType::Integer => CommandOptionType::Integer,
+        OptionType::Number => CommandOptionType::Number,
+        OptionType::Boolean => CommandOptionType::Boolean,
+        OptionType::User => CommandOptionType::User,
+        OptionType::Channel => CommandOptionType::Channel,
+        OptionType::Role => CommandOptionType::Role,
+    };
+    let mut created =
+        CreateCommandOption::new(kind, &option.name, &option.description).required(option.required);
+    if kind == CommandOptionType::String {
+        for choice in option.choices.iter().take(DISCORD_MAX_COMMAND_CHOICES) {
+            created = created.add_string_choice(choice, choice);
+        }
+    }
+    created
+}
+
+pub(crate) fn parse_arguments(options: &[CommandDataOption]) -> Vec<CommandArgument> {
+    options
+        .iter()
+        .filter_map(|option| parse_argument(&option.name, &option.value))
+        .collect()
+}
+
+fn parse_argument(name: &str, value: &CommandDataOptionValue) -> Option<CommandArgument> {
+    let value = match value {
+        CommandDataOptionValue::String(value) => Value::StringValue(value.clone()),
+        CommandDataOptionValue::Integer(value) => Value::IntegerValue(*value),
+        CommandDataOptionValue::Number(value) => Value::NumberValue(*value),
+        CommandDataOptionValue::Boolean(value) => Value::BooleanValue(*value),
+        CommandDataOptionValue::User(user) => Value::UserValue(user.get()),
+        CommandDataOptionValue::Channel(channel) => Value::ChannelValue(channel.get()),
+        CommandDataOptionValue::Role(role) => Value::RoleValue(role.get()),
+        // Only the option types in OptionType can be registered.
+        _ => return None,
+    };
+    Some(CommandArgument {
+        name: name.to_string(),
+        value: Some(value),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::all::{CommandDataOptionValue, UserId};
+
+    use crate::{
+        commands::{check_definitions, parse_argument},
+        error::to_client_error,
+        messages::{CommandDefinition, CommandOption, ErrorCode, command_argument::Value},
+    };
+
+    fn definition(name: &str, description: &str) -> CommandDefinition {
+        CommandDefinition {
+            name: name.to_string(),
+            description: description.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_definitions() {
+        let valid = [
+            definition("status", "Print status"),
+            definition("print-file_2", "Print a file"),
+            definition(&str::repeat("a", 32), &str::repeat("a", 100)),
+        ];
+        assert!(check_definitions(&valid).is_ok());
+
+        let invalid = [
+            definition("", "Print status"),
+            definition("Status", "Print status"),
+            definition("print status", "Print status"),
+            definition(&str::repeat("a", 33), "Print status"),
+            definition("status", ""),
+            definition("status", &str::repeat("a", 101)),
+        ];
+        for command in invalid {
+            let error = check_definitions(&[command]).unwrap_err();
+            assert_eq!(
+                ErrorCode::MalformedMessage as i32,
+                to_client_error(0, &error).code
+            );
+        }
+
+        // Options follow the same rules.
+        let mut command = definition("print", "Print a file");
+        command.options.push(CommandOption {
+            name: "File".to_string(),
+            description: "File to print".to_string(),
+            ..Default::default()
+        });
+        assert!(check_definitions(&[command]).is_err());
+    }
+
+    #[test]
+    fn test_parse_argument_string() {
+        let value = CommandDataOptionValue::String("benchy.gcode".to_string());
+        let argument = parse_argument("file", &value).unwrap();
+        assert_eq!("file", argument.name);
+        assert_eq!(
+            Some(Value::StringValue("benchy.gcode".to_string())),
+            argument.value
+        );
+    }
+
+    #[test]
+    fn test_parse_argument_user() {
+        let value = CommandDataOptionValue::User(UserId::new(1234));
+        let argument = parse_argument("user", &value).unwrap();
+        assert_eq!(Some(Value::UserValue(1234)), argument.value);
+    }
+
+    #[test]
+    fn test_parse_argument_unsupported() {
+        let value = CommandDataOptionValue::SubCommand(vec![]);
+        assert!(parse_argument("sub", &value).is_none());
+    }
+}
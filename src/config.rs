@@ -0,0 +1,430 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{eyre, eyre::eyre};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    embedbuilder::AttachmentLimits,
+    inbound::ConnectionLimits,
+    outbound::{DEFAULT_QUEUE_CAPACITY, SlowConsumerPolicy},
+    tls::acceptor,
+};
+
+pub const DEFAULT_BIND: &str = "0.0.0.0:23416";
+pub const DEFAULT_TLS_BIND: &str = "0.0.0.0:23417";
+pub const DEFAULT_WEBSOCKET_BIND: &str = "0.0.0.0:23418";
+pub const DEFAULT_GRPC_BIND: &str = "0.0.0.0:23419";
+pub const DEFAULT_PRESENCE_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_SESSION_GRACE_PERIOD: Duration = Duration::from_secs(5 * 60);
+pub const DEFAULT_SESSION_BUFFER: usize = 100;
+
+/// Configuration of the discordshim binary, usually loaded from a TOML file.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub discord: DiscordConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub token: Option<String>,
+    /// Read the token from a file instead, e.g. a docker secret.
+    pub token_file: Option<PathBuf>,
+    /// Channel used by the healthcheck binary, clients can bind to it without pairing.
+    pub health_check_channel: Option<u64>,
+}
+
+/// Configuration of the `Server` that clients connect to.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// Listen for plaintext connections on `bind`, can be turned off when TLS is enabled.
+    pub plaintext: bool,
+    pub tls: Option<TlsConfig>,
+    pub websocket: Option<WebSocketConfig>,
+    pub grpc: Option<GrpcConfig>,
+    pub unix: Option<UnixConfig>,
+    /// Running as the shared cloud bot, the presence shows the number of clients instead of
+    /// being set by them.
+    pub cloud: bool,
+    /// Channels that clients may bind to without a pairing code.
+    pub trusted_channels: Vec<u64>,
+    /// Minimum time between presence updates in cloud mode.
+    #[serde(deserialize_with = "secs")]
+    pub presence_interval: Duration,
+    pub queue_capacity: usize,
+    /// How long a disconnected client's session is kept for it to resume, 0 to disable.
+    #[serde(deserialize_with = "secs")]
+    pub session_grace_period: Duration,
+    /// Requests buffered for a disconnected session, the oldest are dropped first.
+    pub session_buffer: usize,
+    pub slow_consumer: SlowConsumerPolicy,
+    pub limits: ConnectionLimits,
+    pub attachments: AttachmentLimits,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: DEFAULT_BIND.to_string(),
+            plaintext: true,
+            tls: None,
+            websocket: None,
+            grpc: None,
+            unix: None,
+            cloud: false,
+            trusted_channels: vec![],
+            presence_interval: DEFAULT_PRESENCE_INTERVAL,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            session_grace_period: DEFAULT_SESSION_GRACE_PERIOD,
+            session_buffer: DEFAULT_SESSION_BUFFER,
+            slow_consumer: SlowConsumerPolicy::DropOldest,
+            limits: ConnectionLimits::default(),
+            attachments: AttachmentLimits::default(),
+        }
+    }
+}
+
+/// TLS listener, used alongside or instead of the plaintext one.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub bind: String,
+    /// PEM certificate chain and private key.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Require clients to present a certificate signed by this CA.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            bind: DEFAULT_TLS_BIND.to_string(),
+            cert: PathBuf::new(),
+            key: PathBuf::new(),
+            client_ca: None,
+        }
+    }
+}
+
+/// WebSocket listener, carrying the same messages as binary WebSocket messages.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub bind: String,
+    /// Accept wss:// connections, using the certificate from `tls`.
+    pub tls: bool,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            bind: DEFAULT_WEBSOCKET_BIND.to_string(),
+            tls: false,
+        }
+    }
+}
+
+/// gRPC listener, serving the `DiscordShim` service from messages.proto without TLS.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub bind: String,
+    /// Serve `GetStats` and `CheckChannel`. They aren't authenticated, and `CheckChannel` tells
+    /// anyone who can reach the listener which channels have clients bound.
+    pub status: bool,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            bind: DEFAULT_GRPC_BIND.to_string(),
+            status: false,
+        }
+    }
+}
+
+/// Unix socket listener for clients on the same host, using the same framing as `bind`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnixConfig {
+    pub path: PathBuf,
+    /// Permissions of the socket file, e.g. `0o660`. Left to the umask if unset.
+    pub mode: Option<u32>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> eyre::Result<Config> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| eyre!("Failed to read config {}: {e}", path.display()))?;
+        Config::parse(&text).map_err(|e| eyre!("Invalid config {}: {e}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> eyre::Result<Config> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Check everything that can be checked before connecting to discord.
+    pub fn validate(&self) -> eyre::Result<()> {
+        self.discord.validate()?;
+        self.server.validate()
+    }
+}
+
+impl DiscordConfig {
+    fn validate(&self) -> eyre::Result<()> {
+        match (&self.token, &self.token_file) {
+            (Some(_), Some(_)) => return Err(eyre!("Set only one of token and token_file")),
+            (None, None) => return Err(eyre!("A discord token or token_file is required")),
+            _ => {}
+        }
+        if self.health_check_channel == Some(0) {
+            return Err(eyre!("health_check_channel must be a channel ID"));
+        }
+        Ok(())
+    }
+
+    /// The bot token, read from `token_file` if set.
+    pub fn token(&self) -> eyre::Result<String> {
+        let token = match (&self.token, &self.token_file) {
+            (Some(token), _) => token.clone(),
+            (None, Some(path)) => fs::read_to_string(path)
+                .map_err(|e| eyre!("Failed to read token file {}: {e}", path.display()))?,
+            (None, None) => return Err(eyre!("A discord token or token_file is required")),
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(eyre!("The discord token is empty"));
+        }
+        Ok(token.to_string())
+    }
+}
+
+impl ServerConfig {
+    pub fn validate(&self) -> eyre::Result<()> {
+        parse_bind(&self.bind)?;
+        match &self.tls {
+            Some(tls) => {
+                parse_bind(&tls.bind)?;
+                // Load the certificates now, so mistakes are reported at startup.
+                acceptor(tls)?;
+            }
+            None if !self.plaintext
+                && self.websocket.is_none()
+                && self.grpc.is_none()
+                && self.unix.is_none() =>
+            {
+                return Err(eyre!(
+                    "Enable at least one of plaintext, tls, websocket, grpc and unix"
+                ));
+            }
+            None => {}
+        }
+        if let Some(grpc) = &self.grpc {
+            parse_bind(&grpc.bind)?;
+        }
+        if let Some(websocket) = &self.websocket {
+            parse_bind(&websocket.bind)?;
+            if websocket.tls && self.tls.is_none() {
+                return Err(eyre!("websocket.tls requires a tls section"));
+            }
+        }
+        if let Some(unix) = &self.unix {
+            if unix.path.as_os_str().is_empty() {
+                return Err(eyre!("unix.path must be set"));
+            }
+            if unix.mode.is_some_and(|mode| mode > 0o777) {
+                return Err(eyre!("unix.mode must be a file mode such as 0o660"));
+            }
+        }
+        if self.trusted_channels.contains(&0) {
+            return Err(eyre!("trusted_channels must be channel IDs"));
+        }
+        if self.queue_capacity == 0 {
+            return Err(eyre!("queue_capacity must be at least 1"));
+        }
+        if self.session_buffer == 0 {
+            return Err(eyre!("session_buffer must be at least 1"));
+        }
+        if self.limits.max_frame_size == 0 || self.limits.frame_timeout.is_zero() {
+            return Err(eyre!("max_frame_size and frame_timeout must be positive"));
+        }
+        if self.limits.idle_timeout.is_some_and(|idle| idle.is_zero()) {
+            return Err(eyre!("idle_timeout must be positive"));
+        }
+        let attachments = &self.attachments;
+        if attachments.chunk_size == 0 || attachments.chunk_size > attachments.max_attachment_size {
+            return Err(eyre!(
+                "chunk_size must be positive and no larger than max_attachment_size"
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn parse_bind(bind: &str) -> eyre::Result<SocketAddr> {
+    bind.parse()
+        .map_err(|e| eyre!("Invalid bind address {bind}: {e}"))
+}
+
+/// Durations are given in seconds in the config file.
+pub(crate) fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+}
+
+pub(crate) fn optional_secs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, time::Duration};
+
+    use crate::{
+        config::{Config, DEFAULT_BIND, GrpcConfig, ServerConfig, TlsConfig, UnixConfig},
+        outbound::SlowConsumerPolicy,
+    };
+
+    #[test]
+    fn test_parse_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(Config::default(), config);
+        assert_eq!(DEFAULT_BIND, config.server.bind);
+        assert!(config.server.validate().is_ok());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            [discord]
+            token = "abc"
+            health_check_channel = 1234
+
+            [server]
+            bind = "127.0.0.1:1234"
+            cloud = true
+            presence_interval = 30
+            session_grace_period = 0
+            slow_consumer = "disconnect"
+
+            [server.limits]
+            max_frame_size = 1024
+            idle_timeout = 600
+
+            [server.attachments]
+            max_attachment_size = 10485760
+
+            [server.grpc]
+            status = true
+
+            [server.unix]
+            path = "/run/discordshim/discordshim.sock"
+            mode = 0o660
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(Some(1234), config.discord.health_check_channel);
+        assert!(config.server.cloud);
+        assert_eq!(Duration::from_secs(30), config.server.presence_interval);
+        assert!(config.server.session_grace_period.is_zero());
+        assert_eq!(SlowConsumerPolicy::Disconnect, config.server.slow_consumer);
+        assert_eq!(1024, config.server.limits.max_frame_size);
+        assert_eq!(
+            Some(Duration::from_secs(600)),
+            config.server.limits.idle_timeout
+        );
+        assert_eq!(10485760, config.server.attachments.max_attachment_size);
+        let grpc = GrpcConfig {
+            status: true,
+            ..Default::default()
+        };
+        assert_eq!(Some(grpc), config.server.grpc);
+        let unix = config.server.unix.unwrap();
+        assert_eq!(Some(0o660), unix.mode);
+    }
+
+    #[test]
+    fn test_parse_unknown_field() {
+        assert!(Config::parse("[server]\nport = 1234\n").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = ServerConfig {
+            bind: "localhost".to_string(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let mut config = ServerConfig::default();
+        config.attachments.chunk_size = 0;
+        assert!(config.validate().is_err());
+
+        let config = ServerConfig {
+            session_buffer: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ServerConfig {
+            plaintext: false,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ServerConfig {
+            plaintext: false,
+            unix: Some(UnixConfig {
+                path: "/tmp/discordshim.sock".into(),
+                mode: None,
+            }),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            unix: Some(UnixConfig::default()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ServerConfig {
+            tls: Some(TlsConfig {
+                cert: "missing.pem".into(),
+                key: "missing.key".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_token_file() {
+        let path = env::temp_dir().join(format!("discordshim-token-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, "abc\n").unwrap();
+
+        let mut config = Config::default();
+        config.discord.token_file = Some(path.clone());
+        assert!(config.validate().is_ok());
+        assert_eq!("abc", config.discord.token().unwrap());
+
+        config.discord.token = Some("def".to_string());
+        assert!(config.validate().is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use color_eyre::eyre;
+use serenity::{
+    all::{
+        ActivityData,
+        CreateActionRow,
+        CreateAllowedMentions,
+        CreateAttachment,
+        CreateCommand,
+        CreateEmbed,
+        CreateInteractionResponse,
+        CreateInteractionResponseFollowup,
+        CreateMessage,
+        EditMessage,
+        MessageReference,
+        MessageReferenceKind,
+        OnlineStatus,
+    },
+    async_trait,
+    builder::Builder,
+    client::Context,
+    model::id::{ChannelId, GuildId, InteractionId, MessageId, UserId},
+};
+
+/// A message to post, the parts of serenity's `CreateMessage` that the shim uses.
+#[derive(Clone, Debug, Default)]
+pub struct OutgoingMessage {
+    pub content: String,
+    pub embed: Option<CreateEmbed>,
+    pub attachments: Vec<CreateAttachment>,
+    pub components: Vec<CreateActionRow>,
+    pub allowed_mentions: CreateAllowedMentions,
+    /// Post as a reply to this message, still posting if it has been deleted.
+    pub reply_to: Option<MessageId>,
+}
+
+/// Changes to a posted message, fields left as None are kept.
+#[derive(Clone, Debug, Default)]
+pub struct MessageEdit {
+    pub content: Option<String>,
+    pub embed: Option<CreateEmbed>,
+    pub components: Option<Vec<CreateActionRow>>,
+    pub allowed_mentions: Option<CreateAllowedMentions>,
+    /// Replaces the message's attachments, unless empty.
+    pub attachments: Vec<CreateAttachment>,
+}
+
+/// The discord operations made on behalf of clients, implemented by serenity's `Context` and by
+/// `FakeDiscord` for tests.
+#[async_trait]
+pub trait Discord: Send + Sync {
+    /// The bot's own user.
+    fn current_user(&self) -> UserId;
+
+    fn set_presence(&self, activity: Option<ActivityData>, status: OnlineStatus);
+
+    async fn send_message(
+        &self,
+        channel: ChannelId,
+        message: OutgoingMessage,
+    ) -> eyre::Result<MessageId>;
+
+    async fn edit_message(
+        &self,
+        channel: ChannelId,
+        message_id: MessageId,
+        edit: MessageEdit,
+    ) -> eyre::Result<MessageId>;
+
+    async fn message_author(
+        &self,
+        channel: ChannelId,
+        message_id: MessageId,
+    ) -> eyre::Result<UserId>;
+
+    async fn delete_message(&self, channel: ChannelId, message_id: MessageId) -> eyre::Result<()>;
+
+    /// The guild of a channel the bot can see, None if it isn't a guild channel.
+    async fn channel_guild(&self, channel: ChannelId) -> eyre::Result<Option<GuildId>>;
+
+    /// Replace the bot's slash commands in a guild.
+    async fn set_guild_commands(
+        &self,
+        guild: GuildId,
+        commands: Vec<CreateCommand>,
+    ) -> eyre::Result<()>;
+
+    async fn respond_to_interaction(
+        &self,
+        interaction: InteractionId,
+        token: &str,
+        response: CreateInteractionResponse,
+    ) -> eyre::Result<()>;
+
+    /// Follow up on an interaction that has been deferred.
+    async fn send_followup(
+        &self,
+        token: &str,
+        message: OutgoingMessage,
+        ephemeral: bool,
+    ) -> eyre::Result<MessageId>;
+}
+
+#[async_trait]
+impl Discord for Context {
+    fn current_user(&self) -> UserId {
+        self.cache.current_user().id
+    }
+
+    fn set_presence(&self, activity: Option<ActivityData>, status: OnlineStatus) {
+        Context::set_presence(self, activity, status);
+    }
+
+    async fn send_message(
+        &self,
+        channel: ChannelId,
+        message: OutgoingMessage,
+    ) -> eyre::Result<MessageId> {
+        let mut builder = CreateMessage::new()
+            .allowed_mentions(message.allowed_mentions)
+            .add_files(message.attachments);
+        if !message.content.is_empty() {
+            builder = builder.content(message.content);
+        }
+        if let Some(embed) = message.embed {
+            builder = builder.embed(embed);
+        }
+        if !message.components.is_empty() {
+            builder = builder.components(message.components);
+        }
+        if let Some(reply_to) = message.reply_to {
+            let reference = MessageReference::new(MessageReferenceKind::Default, channel)
+                .message_id(reply_to)
+                .fail_if_not_exists(false);
+            builder = builder.reference_message(reference);
+        }
+        Ok(channel.send_message(self, builder).await?.id)
+    }
+
+    async fn edit_message(
+        &self,
+        channel: ChannelId,
+        message_id: MessageId,
+        edit: MessageEdit,
+    ) -> eyre::Result<MessageId> {
+        let mut builder = EditMessage::new();
+        if let Some(content) = edit.content {
+            builder = builder.content(content);
+        }
+        if let Some(embed) = edit.embed {
+            builder = builder.embed(embed);
+        }
+        if let Some(components) = edit.components {
+            builder = builder.components(components);
+        }
+        if let Some(allowed_mentions) = edit.allowed_mentions {
+            builder = builder.allowed_mentions(allowed_mentions);
+        }
+        for attachment in edit.attachments {
+            builder = builder.new_attachment(attachment);
+        }
+        Ok(channel.edit_message(self, message_id, builder).await?.id)
+    }
+
+    async fn message_author(
+        &self,
+        channel: ChannelId,
+        message_id: MessageId,
+    ) -> eyre::Result<UserId> {
+        Ok(channel.message(self, message_id).await?.author.id)
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: MessageId) -> eyre::Result<()> {
+        Ok(channel.delete_message(self, message_id).await?)
+    }
+
+    async fn channel_guild(&self, channel: ChannelId) -> eyre::Result<Option<GuildId>> {
+        let channel = channel.to_channel(self).await?;
+        Ok(channel.guild().map(|guild_channel| guild_channel.guild_id))
+    }
+
+    async fn set_guild_commands(
+        &self,
+        guild: GuildId,
+        commands: Vec<CreateCommand>,
+    ) -> eyre::Result<()> {
+        guild.set_commands(self, commands).await?;
+        Ok(())
+    }
+
+    async fn respond_to_interaction(
+        &self,
+        interaction: InteractionId,
+        token: &str,
+        response: CreateInteractionResponse,
+    ) -> eyre::Result<()> {
+        Ok(response.execute(self, (interaction, token)).await?)
+    }
+
+    async fn send_followup(
+        &self,
+        token: &str,
+        message: OutgoingMessage,
+        ephemeral: bool,
+    ) -> eyre::Result<MessageId> {
+        let mut builder = CreateInteractionResponseFollowup::new()
+            .ephemeral(ephemeral)
+            .content(message.content)
+            .allowed_mentions(message.allowed_mentions)
+            .add_files(message.attachments);
+        if let Some(embed) = message.embed {
+            builder = builder.embed(embed);
+        }
+        if !message.components.is_empty() {
+            builder = builder.components(message.components);
+        }
+        Ok(builder.execute(self, (None, token)).await?.id)
+    }
+}
@@ -3,6 +3,7 @@ use std::{
     io::{Cursor, Write},
 };
 
+use serde::Deserialize;
 use serenity::all::CreateAttachment;
 use zip::write::SimpleFileOptions;
 
@@ -15,13 +16,23 @@ pub const DISCORD_MAX_TITLE: usize = 256;
 pub const DISCORD_MAX_DESCRIPTION: usize = 4096;
 pub const DISCORD_MAX_FIELDS: usize = 25;
 pub const DISCORD_MAX_VALUE: usize = 1024;
-//pub const DISCORD_MAX_FOOTER: usize = 2048;
+pub const DISCORD_MAX_FOOTER: usize = 2048;
 pub const DISCORD_MAX_AUTHOR: usize = 256;
 pub const DISCORD_MAX_EMBED_TOTAL: usize = 6000;
 
-fn truncate(string: String, length: usize) -> String {
+pub const DISCORD_MAX_ACTION_ROWS: usize = 5;
+pub const DISCORD_MAX_BUTTONS: usize = 5;
+pub const DISCORD_MAX_SELECT_OPTIONS: usize = 25;
+pub const DISCORD_MAX_CUSTOM_ID: usize = 100;
+
+fn truncate(mut string: String, length: usize) -> String {
     if string.len() > length {
-        return string[0..length].to_string();
+        // Cut before any character that would straddle the limit.
+        let end = (0..=length)
+            .rev()
+            .find(|&i| string.is_char_boundary(i))
+            .unwrap_or(0);
+        string.truncate(end);
     }
     string
 }
@@ -37,12 +48,23 @@ pub(crate) fn build_embeds(embed_content: EmbedContent) -> Vec<EmbedContent> {
         "\u{200b}".to_string()
     };
     first.snapshot = embed_content.snapshot;
+    first.url = embed_content.url;
+    first.thumbnail = embed_content.thumbnail;
+    first.thumbnail_url = embed_content.thumbnail_url;
+    first.image_url = embed_content.image_url;
 
     let author = truncate(embed_content.author, DISCORD_MAX_AUTHOR);
     first.author.clone_from(&author);
+    first
+        .author_icon_url
+        .clone_from(&embed_content.author_icon_url);
+    first.author_url.clone_from(&embed_content.author_url);
     first.color = embed_content.color;
 
-    total_chars = first.title.len() + first.description.len() + first.author.len();
+    // The footer goes on the last embed, which isn't known yet, so count it toward every embed.
+    let footer = truncate(embed_content.footer, DISCORD_MAX_FOOTER);
+
+    total_chars = first.title.len() + first.description.len() + first.author.len() + footer.len();
 
     let mut last = first;
 
@@ -61,20 +83,53 @@ pub(crate) fn build_embeds(embed_content: EmbedContent) -> Vec<EmbedContent> {
             last = EmbedContent::default();
             last.description = "\u{200b}".to_string();
             last.author.clone_from(&author);
+            last.author_icon_url
+                .clone_from(&embed_content.author_icon_url);
+            last.author_url.clone_from(&embed_content.author_url);
             last.color = embed_content.color;
-            total_chars = last.title.len() + last.description.len() + last.author.len();
+            total_chars =
+                last.title.len() + last.description.len() + last.author.len() + footer.len();
         }
 
         last.textfield.push(trimmed_field);
         total_chars += title.len() + text.len();
     }
 
+    last.components = embed_content.components;
+    last.footer = footer;
+    last.footer_icon_url = embed_content.footer_icon_url;
+    last.timestamp = embed_content.timestamp;
     embeds.push(last);
     embeds
 }
 
-pub(crate) fn split_file(filename: String, filedata: &[u8]) -> Vec<(String, CreateAttachment)> {
-    if filedata.len() < DISCORD_MAX_ATTACHMENT_SIZE {
+/// Limits used to decide when and how to split files across several attachments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttachmentLimits {
+    /// Files this size or larger are zipped and split.
+    pub max_attachment_size: usize,
+    /// Size of each part of a split file.
+    pub chunk_size: usize,
+}
+
+impl Default for AttachmentLimits {
+    fn default() -> Self {
+        AttachmentLimits {
+            max_attachment_size: DISCORD_MAX_ATTACHMENT_SIZE,
+            chunk_size: ONE_MEGABYTE,
+        }
+    }
+}
+
+/// The attachments to post a file as. Files of `max_attachment_size` or larger are zipped and the
+/// zip split into `chunk_size` parts, named `<filename>.zip.000` onwards.
+pub fn split_file(
+    filename: String,
+    filedata: &[u8],
+    limits: &AttachmentLimits,
+) -> Vec<(String, CreateAttachment)> {
+    if filedata.len() < limits.max_attachment_size {
         let mut attachments = vec![];
         let filename2 = filename.clone();
         attachments.push((
@@ -93,7 +148,7 @@ pub(crate) fn split_file(filename: String, filedata: &[u8]) -> Vec<(String, Crea
         zip.write_all(filedata).unwrap();
         let zipdata = zip.finish().unwrap().into_inner();
 
-        let chunks = zipdata.chunks(ONE_MEGABYTE);
+        let chunks = zipdata.chunks(limits.chunk_size);
         for (i, chunk) in chunks.enumerate() {
             let zipfilename = format!("{}.zip.{:0>3}", filename, i);
             let mut data = vec![0u8; chunk.len()];
@@ -0,0 +1,113 @@
+use std::fmt;
+
+use color_eyre::eyre;
+use serenity::{
+    Error as SerenityError,
+    all::{HttpError, ModelError},
+};
+
+use crate::messages::{Error, ErrorCode};
+
+// Discord JSON error codes, see https://discord.com/developers/docs/topics/opcodes-and-status-codes#json
+const DISCORD_UNKNOWN_CHANNEL: isize = 10003;
+const DISCORD_REQUEST_TOO_LARGE: isize = 40005;
+const DISCORD_MISSING_ACCESS: isize = 50001;
+const DISCORD_MISSING_PERMISSIONS: isize = 50013;
+
+/// An error caused by the client's request, reported back to it with a specific code.
+#[derive(Debug)]
+pub(crate) struct ShimError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl ShimError {
+    pub(crate) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ShimError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ShimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ShimError {}
+
+/// Build the `Error` sent to the client when handling the `Response` with correlation ID `id` failed.
+pub(crate) fn to_client_error(id: u64, error: &eyre::Report) -> Error {
+    let code = if let Some(shim_error) = error.downcast_ref::<ShimError>() {
+        shim_error.code
+    } else if let Some(discord_error) = error.downcast_ref::<SerenityError>() {
+        discord_error_code(discord_error)
+    } else {
+        ErrorCode::Unspecified
+    };
+    Error {
+        code: code.into(),
+        message: error.to_string(),
+        id,
+    }
+}
+
+fn discord_error_code(error: &SerenityError) -> ErrorCode {
+    match error {
+        SerenityError::Http(HttpError::UnsuccessfulRequest(response)) => {
+            http_error_code(response.status_code.as_u16(), response.error.code)
+        }
+        SerenityError::Model(
+            ModelError::MessageTooLong(_) | ModelError::EmbedTooLarge(_) | ModelError::EmbedAmount,
+        ) => ErrorCode::PayloadTooLarge,
+        _ => ErrorCode::DiscordError,
+    }
+}
+
+fn http_error_code(status: u16, code: isize) -> ErrorCode {
+    match (status, code) {
+        (429, _) => ErrorCode::RateLimited,
+        (413, _) | (_, DISCORD_REQUEST_TOO_LARGE) => ErrorCode::PayloadTooLarge,
+        (_, DISCORD_UNKNOWN_CHANNEL) => ErrorCode::UnknownChannel,
+        (_, DISCORD_MISSING_ACCESS | DISCORD_MISSING_PERMISSIONS) => ErrorCode::MissingAccess,
+        _ => ErrorCode::DiscordError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use crate::{
+        error::{ShimError, http_error_code, to_client_error},
+        messages::ErrorCode,
+    };
+
+    #[test]
+    fn test_http_error_code() {
+        assert_eq!(ErrorCode::RateLimited, http_error_code(429, 0));
+        assert_eq!(ErrorCode::PayloadTooLarge, http_error_code(413, 0));
+        assert_eq!(ErrorCode::PayloadTooLarge, http_error_code(400, 40005));
+        assert_eq!(ErrorCode::UnknownChannel, http_error_code(404, 10003));
+        assert_eq!(ErrorCode::MissingAccess, http_error_code(403, 50001));
+        assert_eq!(ErrorCode::MissingAccess, http_error_code(403, 50013));
+        assert_eq!(ErrorCode::DiscordError, http_error_code(500, 0));
+    }
+
+    #[test]
+    fn test_to_client_error_shim_error() {
+        let report = ShimError::new(ErrorCode::NotPaired, "not paired").into();
+        let error = to_client_error(42, &report);
+        assert_eq!(ErrorCode::NotPaired, error.code());
+        assert_eq!("not paired", error.message);
+        assert_eq!(42, error.id);
+    }
+
+    #[test]
+    fn test_to_client_error_other() {
+        let error = to_client_error(0, &eyre!("something else"));
+        assert_eq!(ErrorCode::Unspecified, error.code());
+    }
+}
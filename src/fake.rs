@@ -0,0 +1,389 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use color_eyre::eyre;
+use serenity::{
+    all::{ActivityData, CreateCommand, CreateInteractionResponse, OnlineStatus},
+    async_trait,
+    model::id::{ChannelId, GuildId, InteractionId, MessageId, UserId},
+};
+
+use crate::{
+    discord::{Discord, MessageEdit, OutgoingMessage},
+    error::ShimError,
+    messages::ErrorCode,
+};
+
+/// A message in one of the fake's channels.
+#[derive(Clone, Debug)]
+pub struct FakeMessage {
+    pub id: MessageId,
+    pub channel: ChannelId,
+    pub author: UserId,
+    pub message: OutgoingMessage,
+    /// Number of times the message was edited.
+    pub edits: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeFollowup {
+    pub id: MessageId,
+    pub token: String,
+    pub message: OutgoingMessage,
+    pub ephemeral: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeInteractionResponse {
+    pub interaction: InteractionId,
+    pub token: String,
+    pub response: CreateInteractionResponse,
+}
+
+#[derive(Default)]
+struct FakeState {
+    last_id: u64,
+    // Channels the bot can see, and their guild.
+    channels: HashMap<ChannelId, Option<GuildId>>,
+    messages: Vec<FakeMessage>,
+    followups: Vec<FakeFollowup>,
+    interaction_responses: Vec<FakeInteractionResponse>,
+    presences: Vec<(Option<ActivityData>, OnlineStatus)>,
+    commands: HashMap<GuildId, Vec<CreateCommand>>,
+    reject_commands: bool,
+}
+
+impl FakeState {
+    fn next_id(&mut self) -> MessageId {
+        self.last_id += 1;
+        MessageId::new(self.last_id)
+    }
+
+    fn check_channel(&self, channel: ChannelId) -> eyre::Result<()> {
+        if !self.channels.contains_key(&channel) {
+            return Err(unknown_channel(channel));
+        }
+        Ok(())
+    }
+
+    fn message_mut(
+        &mut self,
+        channel: ChannelId,
+        message_id: MessageId,
+    ) -> eyre::Result<&mut FakeMessage> {
+        self.check_channel(channel)?;
+        self.messages
+            .iter_mut()
+            .find(|message| message.id == message_id && message.channel == channel)
+            .ok_or_else(|| {
+                ShimError::new(
+                    ErrorCode::DiscordError,
+                    format!("Unknown message {message_id}"),
+                )
+                .into()
+            })
+    }
+}
+
+/// An in-memory discord that records everything sent to it, so tests can run a `Server` without
+/// a bot token. Channels have to be added before clients can post to them.
+pub struct FakeDiscord {
+    user: UserId,
+    state: Mutex<FakeState>,
+}
+
+impl Default for FakeDiscord {
+    fn default() -> Self {
+        Self::new(UserId::new(1))
+    }
+}
+
+impl FakeDiscord {
+    /// A fake where the bot is `user`.
+    pub fn new(user: UserId) -> FakeDiscord {
+        FakeDiscord {
+            user,
+            state: Mutex::new(FakeState::default()),
+        }
+    }
+
+    /// Make a channel visible to the bot, `guild` is None for DMs.
+    pub fn add_channel(&self, channel: ChannelId, guild: Option<GuildId>) {
+        self.state.lock().unwrap().channels.insert(channel, guild);
+    }
+
+    /// Post a message as another user, e.g. to check the shim won't delete it.
+    pub fn add_message(&self, channel: ChannelId, author: UserId, content: &str) -> MessageId {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        state.messages.push(FakeMessage {
+            id,
+            channel,
+            author,
+            message: OutgoingMessage {
+                content: content.to_string(),
+                ..Default::default()
+            },
+            edits: 0,
+        });
+        id
+    }
+
+    /// Messages that haven't been deleted, oldest first.
+    pub fn messages(&self, channel: ChannelId) -> Vec<FakeMessage> {
+        let state = self.state.lock().unwrap();
+        state
+            .messages
+            .iter()
+            .filter(|message| message.channel == channel)
+            .cloned()
+            .collect()
+    }
+
+    pub fn followups(&self) -> Vec<FakeFollowup> {
+        self.state.lock().unwrap().followups.clone()
+    }
+
+    pub fn interaction_responses(&self) -> Vec<FakeInteractionResponse> {
+        self.state.lock().unwrap().interaction_responses.clone()
+    }
+
+    /// Every presence that was set, oldest first.
+    pub fn presences(&self) -> Vec<(Option<ActivityData>, OnlineStatus)> {
+        self.state.lock().unwrap().presences.clone()
+    }
+
+    /// Forget the messages, followups and interaction responses sent so far, so long load tests
+    /// don't run out of memory. IDs keep counting up.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.messages.clear();
+        state.followups.clear();
+        state.interaction_responses.clear();
+    }
+
+    /// Make discord reject slash command updates, as it does for definitions it considers invalid.
+    pub fn reject_commands(&self, reject: bool) {
+        self.state.lock().unwrap().reject_commands = reject;
+    }
+
+    /// The slash commands registered in a guild.
+    pub fn commands(&self, guild: GuildId) -> Vec<CreateCommand> {
+        let state = self.state.lock().unwrap();
+        state.commands.get(&guild).cloned().unwrap_or_default()
+    }
+}
+
+fn unknown_channel(channel: ChannelId) -> eyre::Report {
+    ShimError::new(
+        ErrorCode::UnknownChannel,
+        format!("Unknown channel {channel}"),
+    )
+    .into()
+}
+
+#[async_trait]
+impl Discord for FakeDiscord {
+    fn current_user(&self) -> UserId {
+        self.user
+    }
+
+    fn set_presence(&self, activity: Option<ActivityData>, status: OnlineStatus) {
+        self.state
+            .lock()
+            .unwrap()
+            .presences
+            .push((activity, status));
+    }
+
+    async fn send_message(
+        &self,
+        channel: ChannelId,
+        message: OutgoingMessage,
+    ) -> eyre::Result<MessageId> {
+        let mut state = self.state.lock().unwrap();
+        state.check_channel(channel)?;
+        let id = state.next_id();
+        state.messages.push(FakeMessage {
+            id,
+            channel,
+            author: self.user,
+            message,
+            edits: 0,
+        });
+        Ok(id)
+    }
+
+    async fn edit_message(
+        &self,
+        channel: ChannelId,
+        message_id: MessageId,
+        edit: MessageEdit,
+    ) -> eyre::Result<MessageId> {
+        let mut state = self.state.lock().unwrap();
+        let posted = state.message_mut(channel, message_id)?;
+        let message = &mut posted.message;
+        if let Some(content) = edit.content {
+            message.content = content;
+        }
+        if let Some(embed) = edit.embed {
+            message.embed = Some(embed);
+        }
+        if let Some(components) = edit.components {
+            message.components = components;
+        }
+        if let Some(allowed_mentions) = edit.allowed_mentions {
+            message.allowed_mentions = allowed_mentions;
+        }
+        if !edit.attachments.is_empty() {
+            message.attachments = edit.attachments;
+        }
+        posted.edits += 1;
+        Ok(message_id)
+    }
+
+    async fn message_author(
+        &self,
+        channel: ChannelId,
+        message_id: MessageId,
+    ) -> eyre::Result<UserId> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.message_mut(channel, message_id)?.author)
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: MessageId) -> eyre::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.message_mut(channel, message_id)?;
+        state.messages.retain(|message| message.id != message_id);
+        Ok(())
+    }
+
+    async fn channel_guild(&self, channel: ChannelId) -> eyre::Result<Option<GuildId>> {
+        let state = self.state.lock().unwrap();
+        match state.channels.get(&channel) {
+            Some(guild) => Ok(*guild),
+            None => Err(unknown_channel(channel)),
+        }
+    }
+
+    async fn set_guild_commands(
+        &self,
+        guild: GuildId,
+        commands: Vec<CreateCommand>,
+    ) -> eyre::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.reject_commands {
+            return Err(ShimError::new(
+                ErrorCode::DiscordError,
+                format!("Invalid commands for guild {guild}"),
+            )
+            .into());
+        }
+        state.commands.insert(guild, commands);
+        Ok(())
+    }
+
+    async fn respond_to_interaction(
+        &self,
+        interaction: InteractionId,
+        token: &str,
+        response: CreateInteractionResponse,
+    ) -> eyre::Result<()> {
+        let response = FakeInteractionResponse {
+            interaction,
+            token: token.to_string(),
+            response,
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .interaction_responses
+            .push(response);
+        Ok(())
+    }
+
+    async fn send_followup(
+        &self,
+        token: &str,
+        message: OutgoingMessage,
+        ephemeral: bool,
+    ) -> eyre::Result<MessageId> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        state.followups.push(FakeFollowup {
+            id,
+            token: token.to_string(),
+            message,
+            ephemeral,
+        });
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::{
+        all::CreateEmbed,
+        model::id::{ChannelId, MessageId, UserId},
+    };
+
+    use crate::{
+        discord::{Discord, MessageEdit, OutgoingMessage},
+        fake::FakeDiscord,
+    };
+
+    #[async_std::test]
+    async fn test_unknown_channel() {
+        let discord = FakeDiscord::default();
+        let message = OutgoingMessage::default();
+        assert!(
+            discord
+                .send_message(ChannelId::new(2), message)
+                .await
+                .is_err()
+        );
+        assert!(discord.channel_guild(ChannelId::new(2)).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_edit_and_delete() {
+        let discord = FakeDiscord::default();
+        let channel = ChannelId::new(2);
+        discord.add_channel(channel, None);
+        let message = OutgoingMessage {
+            content: "content".to_string(),
+            ..Default::default()
+        };
+        let id = discord.send_message(channel, message).await.unwrap();
+
+        let edit = MessageEdit {
+            embed: Some(CreateEmbed::new().title("title")),
+            ..Default::default()
+        };
+        discord.edit_message(channel, id, edit).await.unwrap();
+        let messages = discord.messages(channel);
+        assert_eq!(1, messages[0].edits);
+        assert_eq!("content", messages[0].message.content);
+        assert!(messages[0].message.embed.is_some());
+
+        let other = discord.add_message(channel, UserId::new(3), "hello");
+        assert_eq!(
+            UserId::new(3),
+            discord.message_author(channel, other).await.unwrap()
+        );
+        discord.delete_message(channel, id).await.unwrap();
+        assert_eq!(
+            vec![other],
+            discord
+                .messages(channel)
+                .iter()
+                .map(|m| m.id)
+                .collect::<Vec<_>>()
+        );
+        assert!(
+            discord
+                .delete_message(channel, MessageId::new(1234))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::{pin::Pin, sync::Arc};
+
+use async_std::{channel::bounded, task};
+use futures::{Stream, StreamExt};
+use log::info;
+use serenity::model::id::ChannelId;
+use tonic::{Status, Streaming};
+
+use crate::{
+    discord::Discord,
+    inbound::FrameReader,
+    messages::{
+        ChannelCheck,
+        ChannelStatus,
+        Request,
+        Response,
+        ServerStats,
+        StatsRequest,
+        discord_shim_server::{DiscordShim, DiscordShimServer},
+    },
+    outbound::FrameWriter,
+    server::Server,
+};
+
+/// Serves the shim over gRPC, sharing the clients of `server`.
+pub(crate) struct GrpcService {
+    server: Arc<Server>,
+    discord: Arc<dyn Discord>,
+    // Whether GetStats and CheckChannel are served.
+    status: bool,
+}
+
+impl GrpcService {
+    pub(crate) fn new(
+        server: Arc<Server>,
+        discord: Arc<dyn Discord>,
+        max_frame_size: usize,
+        status: bool,
+    ) -> DiscordShimServer<GrpcService> {
+        let service = GrpcService {
+            server,
+            discord,
+            status,
+        };
+        DiscordShimServer::new(service).max_decoding_message_size(max_frame_size)
+    }
+
+    fn check_status(&self) -> Result<(), Status> {
+        if !self.status {
+            return Err(Status::permission_denied(
+                "Set status = true in [server.grpc] to serve GetStats and CheckChannel",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl DiscordShim for GrpcService {
+    type SessionStream = Pin<Box<dyn Stream<Item = Result<Request, Status>> + Send>>;
+
+    async fn session(
+        &self,
+        request: tonic::Request<Streaming<Response>>,
+    ) -> Result<tonic::Response<Self::SessionStream>, Status> {
+        let peer_addr = request
+            .remote_addr()
+            .map_or_else(|| String::from("unknown"), |addr| addr.to_string());
+        info!("Received gRPC connection from: {peer_addr}");
+        // The client's outbound queue does the buffering.
+        let (sender, receiver) = bounded(1);
+
+        let server = self.server.clone();
+        let discord = self.discord.clone();
+        task::spawn(async move {
+            let reader = FrameReader::Grpc(Box::new(request.into_inner()));
+            let writer = FrameWriter::Grpc(sender);
+            server
+                .serve_client(None, peer_addr.clone(), reader, writer, discord)
+                .await;
+            info!("Dropped gRPC connection from: {peer_addr}");
+        });
+        Ok(tonic::Response::new(Box::pin(receiver.map(Ok))))
+    }
+
+    async fn get_stats(
+        &self,
+        _request: tonic::Request<StatsRequest>,
+    ) -> Result<tonic::Response<ServerStats>, Status> {
+        self.check_status()?;
+        Ok(tonic::Response::new(self.server.server_stats().await))
+    }
+
+    async fn check_channel(
+        &self,
+        request: tonic::Request<ChannelCheck>,
+    ) -> Result<tonic::Response<ChannelStatus>, Status> {
+        self.check_status()?;
+        let channel_id = request.into_inner().channel_id;
+        if channel_id == 0 {
+            return Err(Status::invalid_argument("channel_id must be set"));
+        }
+        let status = self
+            .server
+            .channel_status(self.discord.as_ref(), ChannelId::new(channel_id))
+            .await;
+        Ok(tonic::Response::new(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tonic::Code;
+
+    use crate::{
+        fake::FakeDiscord,
+        grpc::GrpcService,
+        messages::{ChannelCheck, StatsRequest, discord_shim_server::DiscordShim},
+        server::Server,
+    };
+
+    fn service(status: bool) -> GrpcService {
+        GrpcService {
+            server: Arc::new(Server::default()),
+            discord: Arc::new(FakeDiscord::default()),
+            status,
+        }
+    }
+
+    #[async_std::test]
+    async fn test_status_disabled() {
+        let disabled = service(false);
+        let stats = disabled.get_stats(tonic::Request::new(StatsRequest {}));
+        assert_eq!(Code::PermissionDenied, stats.await.unwrap_err().code());
+        let check = ChannelCheck { channel_id: 1234 };
+        let status = disabled.check_channel(tonic::Request::new(check));
+        assert_eq!(Code::PermissionDenied, status.await.unwrap_err().code());
+
+        let enabled = service(true);
+        let check = ChannelCheck { channel_id: 1234 };
+        let status = enabled.check_channel(tonic::Request::new(check)).await;
+        assert!(!status.unwrap().into_inner().accessible);
+    }
+}
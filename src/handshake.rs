@@ -0,0 +1,105 @@
+use color_eyre::eyre;
+
+use crate::{
+    error::ShimError,
+    messages::{ErrorCode, Feature, Hello, ServerHello},
+};
+
+/// Version of the protocol spoken by the shim. Clients that don't send a `Hello` speak version 1.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Everything the shim supports, announced in its `ServerHello`.
+const FEATURES: [Feature; 6] = [
+    Feature::Acks,
+    Feature::EditMessages,
+    Feature::Components,
+    Feature::SlashCommands,
+    Feature::MentionPolicy,
+    Feature::Sessions,
+];
+
+/// What the client said about itself in its `Hello`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ClientInfo {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) capabilities: Vec<Feature>,
+}
+
+impl ClientInfo {
+    pub(crate) fn supports(&self, feature: Feature) -> bool {
+        self.capabilities.contains(&feature)
+    }
+}
+
+/// Check that the shim can talk to the client, returning its reply and what the client supports.
+pub(crate) fn handshake(hello: Hello) -> eyre::Result<(ServerHello, ClientInfo)> {
+    if hello.protocol_version == 0 || hello.protocol_version > PROTOCOL_VERSION {
+        return Err(ShimError::new(
+            ErrorCode::UnsupportedProtocol,
+            format!(
+                "Protocol version {} is not supported, discordshim {} speaks versions 1 to {PROTOCOL_VERSION}",
+                hello.protocol_version,
+                env!("CARGO_PKG_VERSION"),
+            ),
+        )
+        .into());
+    }
+
+    // Capabilities this shim doesn't know about are ignored.
+    let capabilities = hello.capabilities().collect();
+    let reply = ServerHello {
+        protocol_version: PROTOCOL_VERSION,
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        features: FEATURES.iter().map(|&feature| feature.into()).collect(),
+    };
+    let client = ClientInfo {
+        name: hello.client_name,
+        version: hello.client_version,
+        capabilities,
+    };
+    Ok((reply, client))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::to_client_error,
+        handshake::{PROTOCOL_VERSION, handshake},
+        messages::{ErrorCode, Feature, Hello},
+    };
+
+    #[test]
+    fn test_handshake() {
+        let hello = Hello {
+            protocol_version: 1,
+            client_name: "OctoPrint-DiscordRemote".to_string(),
+            client_version: "4.0.0".to_string(),
+            capabilities: vec![Feature::Sessions.into(), 1234],
+        };
+        let (reply, client) = handshake(hello).unwrap();
+        assert_eq!(PROTOCOL_VERSION, reply.protocol_version);
+        assert_eq!(env!("CARGO_PKG_VERSION"), reply.server_version);
+        assert!(reply.features().any(|feature| feature == Feature::Sessions));
+
+        assert_eq!("OctoPrint-DiscordRemote", client.name);
+        assert_eq!(vec![Feature::Sessions], client.capabilities);
+        assert!(client.supports(Feature::Sessions));
+        assert!(!client.supports(Feature::Acks));
+    }
+
+    #[test]
+    fn test_handshake_unsupported_version() {
+        for protocol_version in [0, PROTOCOL_VERSION + 1] {
+            let hello = Hello {
+                protocol_version,
+                ..Default::default()
+            };
+            let error = handshake(hello).unwrap_err();
+            assert_eq!(
+                ErrorCode::UnsupportedProtocol as i32,
+                to_client_error(0, &error).code
+            );
+        }
+    }
+}
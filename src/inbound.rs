@@ -0,0 +1,258 @@
+use std::{pin::pin, sync::Arc, time::Duration};
+
+use async_std::{
+    future::timeout,
+    io::{Read, ReadExt},
+};
+use async_tungstenite::tungstenite::{Error as WebSocketError, Message, error::CapacityError};
+use byteorder::{ByteOrder, LittleEndian};
+use color_eyre::{eyre, eyre::eyre};
+use futures::{
+    StreamExt,
+    future::{Either, select},
+};
+use log::debug;
+use prost::{DecodeError, Message as _};
+use serde::Deserialize;
+use tonic::{Code, Streaming};
+
+use crate::{
+    config::{optional_secs, secs},
+    error::ShimError,
+    messages::{ErrorCode, Response},
+    transport::{ReadSignal, WebSocketReader},
+};
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits on what a client may send, so a single peer can't exhaust memory or hold a socket open.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionLimits {
+    /// Largest frame accepted, larger frames disconnect the client.
+    pub max_frame_size: usize,
+    /// How long a client may take to send the rest of a frame once it has started, and to read
+    /// the messages still queued for it when it is disconnected.
+    #[serde(deserialize_with = "secs")]
+    pub frame_timeout: Duration,
+    /// How long a client may go without sending anything, None to wait forever.
+    #[serde(deserialize_with = "optional_secs")]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            frame_timeout: DEFAULT_FRAME_TIMEOUT,
+            idle_timeout: None,
+        }
+    }
+}
+
+/// Where a connection's frames are read from.
+pub(crate) enum FrameReader {
+    Stream(Box<dyn Read + Send + Unpin>),
+    WebSocket(WebSocketReader, Arc<ReadSignal>),
+    // gRPC messages arrive decoded, with the size limit enforced by the service.
+    Grpc(Box<Streaming<Response>>),
+}
+
+impl FrameReader {
+    /// Read the next message, the inner error is set if it couldn't be decoded. Limit violations
+    /// are returned as a `ShimError`.
+    pub(crate) async fn read(
+        &mut self,
+        limits: &ConnectionLimits,
+    ) -> eyre::Result<Result<Response, DecodeError>> {
+        let frame = match self {
+            FrameReader::Stream(stream) => read_frame(stream, limits).await?,
+            FrameReader::WebSocket(websocket, signal) => {
+                read_message(websocket, signal, limits).await?
+            }
+            FrameReader::Grpc(stream) => return Ok(Ok(read_grpc(stream, limits).await?)),
+        };
+        debug!("Incoming response, {} bytes long.", frame.len());
+        Ok(Response::decode(frame.as_slice()))
+    }
+}
+
+/// Read one length-prefixed frame. Limit violations are returned as a `ShimError`, so they can be
+/// reported to the client before disconnecting it.
+pub(crate) async fn read_frame<R: Read + Unpin>(
+    stream: &mut R,
+    limits: &ConnectionLimits,
+) -> eyre::Result<Vec<u8>> {
+    let length_buf = &mut [0u8; 4];
+    // The first byte can take as long as the idle timeout, the rest of the frame should follow promptly.
+    match limits.idle_timeout {
+        Some(idle) => timeout(idle, stream.read_exact(&mut length_buf[..1]))
+            .await
+            .map_err(|_| idle_timeout(idle))??,
+        None => stream.read_exact(&mut length_buf[..1]).await?,
+    }
+
+    let length = timeout(limits.frame_timeout, async {
+        stream.read_exact(&mut length_buf[1..]).await?;
+        let length = LittleEndian::read_u32(length_buf) as usize;
+        if length > limits.max_frame_size {
+            return Err(too_large(length, limits.max_frame_size).into());
+        }
+        eyre::Ok(length)
+    });
+    let length = length.await.map_err(|_| frame_timeout(limits))??;
+
+    let mut buf = vec![0u8; length];
+    timeout(limits.frame_timeout, stream.read_exact(&mut buf))
+        .await
+        .map_err(|_| frame_timeout(limits))??;
+    Ok(buf)
+}
+
+// The WebSocket only returns whole messages, so `signal` tells when one starts arriving, after
+// which it has `frame_timeout` to finish like a frame. Bytes that were read along with the previous
+// message don't count as a start. The size limit is enforced by the WebSocket config.
+async fn read_message(
+    websocket: &mut WebSocketReader,
+    signal: &ReadSignal,
+    limits: &ConnectionLimits,
+) -> eyre::Result<Vec<u8>> {
+    loop {
+        signal.arm();
+        let mut next = websocket.next();
+        let started = pin!(async {
+            match limits.idle_timeout {
+                Some(idle) => timeout(idle, signal.arrived())
+                    .await
+                    .map_err(|_| idle_timeout(idle)),
+                None => {
+                    signal.arrived().await;
+                    Ok(())
+                }
+            }
+        });
+        let message = match select(&mut next, started).await {
+            Either::Left((message, _)) => message,
+            Either::Right((started, _)) => {
+                started?;
+                timeout(limits.frame_timeout, next)
+                    .await
+                    .map_err(|_| frame_timeout(limits))?
+            }
+        };
+        match message {
+            None | Some(Ok(Message::Close(_))) => return Err(eyre!("Connection closed")),
+            Some(Ok(Message::Binary(data))) => return Ok(data.into()),
+            Some(Ok(message)) => debug!("Ignoring WebSocket message {message:?}"),
+            Some(Err(WebSocketError::Capacity(CapacityError::MessageTooLong {
+                size,
+                max_size,
+            }))) => {
+                return Err(too_large(size, max_size).into());
+            }
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+async fn read_grpc(
+    stream: &mut Streaming<Response>,
+    limits: &ConnectionLimits,
+) -> eyre::Result<Response> {
+    let message = match limits.idle_timeout {
+        Some(idle) => timeout(idle, stream.message())
+            .await
+            .map_err(|_| idle_timeout(idle))?,
+        None => stream.message().await,
+    };
+    match message {
+        Ok(Some(response)) => Ok(response),
+        Ok(None) => Err(eyre!("Connection closed")),
+        Err(status) if status.code() == Code::OutOfRange => {
+            Err(ShimError::new(ErrorCode::FrameTooLarge, status.message().to_string()).into())
+        }
+        Err(status) => Err(status.into()),
+    }
+}
+
+fn idle_timeout(idle: Duration) -> ShimError {
+    ShimError::new(
+        ErrorCode::Timeout,
+        format!("No message received for {}s", idle.as_secs()),
+    )
+}
+
+fn too_large(length: usize, max_frame_size: usize) -> ShimError {
+    ShimError::new(
+        ErrorCode::FrameTooLarge,
+        format!("Message is {length} bytes, the limit is {max_frame_size} bytes"),
+    )
+}
+
+fn frame_timeout(limits: &ConnectionLimits) -> ShimError {
+    ShimError::new(
+        ErrorCode::Timeout,
+        format!(
+            "Message not received within {}s",
+            limits.frame_timeout.as_secs()
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_std::{
+        io::{Cursor, WriteExt},
+        os::unix::net::UnixStream,
+    };
+
+    use crate::{
+        error::to_client_error,
+        inbound::{ConnectionLimits, read_frame},
+        messages::ErrorCode,
+    };
+
+    #[async_std::test]
+    async fn test_read_frame() {
+        let mut stream = Cursor::new(vec![3, 0, 0, 0, 1, 2, 3]);
+        let frame = read_frame(&mut stream, &ConnectionLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![1, 2, 3], frame);
+    }
+
+    #[async_std::test]
+    async fn test_read_frame_too_large() {
+        let limits = ConnectionLimits {
+            max_frame_size: 2,
+            ..Default::default()
+        };
+        let mut stream = Cursor::new(vec![3, 0, 0, 0, 1, 2, 3]);
+        let error = read_frame(&mut stream, &limits).await.unwrap_err();
+        assert_eq!(
+            ErrorCode::FrameTooLarge as i32,
+            to_client_error(0, &error).code
+        );
+    }
+
+    #[async_std::test]
+    async fn test_read_frame_timeout() {
+        let limits = ConnectionLimits {
+            frame_timeout: Duration::from_millis(10),
+            idle_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let (mut stream, mut peer) = UnixStream::pair().unwrap();
+
+        let error = read_frame(&mut stream, &limits).await.unwrap_err();
+        assert_eq!(ErrorCode::Timeout as i32, to_client_error(0, &error).code);
+
+        // A half-sent frame times out too.
+        peer.write_all(&[3, 0, 0, 0, 1]).await.unwrap();
+        let error = read_frame(&mut stream, &limits).await.unwrap_err();
+        assert_eq!(ErrorCode::Timeout as i32, to_client_error(0, &error).code);
+    }
+}
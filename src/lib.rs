@@ -1,6 +1,20 @@
-mod embedbuilder;
+pub mod client;
+mod commands;
+pub mod config;
+pub mod discord;
+pub mod embedbuilder;
+mod error;
+#[cfg(any(test, feature = "fake"))]
+pub mod fake;
+mod grpc;
+pub mod handshake;
+pub mod inbound;
+mod mentions;
+pub mod outbound;
 pub mod server;
 mod test;
+pub mod tls;
+mod transport;
 pub mod messages {
     include!(concat!(env!("OUT_DIR"), "/discord_shim.rs"));
 }
@@ -0,0 +1,209 @@
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+use serenity::{
+    all::CreateAllowedMentions,
+    model::id::{ChannelId, RoleId, UserId},
+};
+
+use crate::messages::{EmbedContent, MentionPolicy};
+
+static MENTION_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<@!?(\d+)>|<@&(\d+)>|<#(\d+)>|@(everyone|here)").unwrap());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mention {
+    User(UserId),
+    Role(RoleId),
+    Channel(ChannelId),
+    Everyone,
+    Here,
+}
+
+/// Find the mentions in every text part of an embed, in order of first appearance.
+pub(crate) fn extract_mentions(e: &EmbedContent) -> Vec<Mention> {
+    let mut mentions = vec![];
+    parse_mentions(&e.title, &mut mentions);
+    parse_mentions(&e.description, &mut mentions);
+    parse_mentions(&e.author, &mut mentions);
+    parse_mentions(&e.footer, &mut mentions);
+    for field in &e.textfield {
+        parse_mentions(&field.title, &mut mentions);
+        parse_mentions(&field.text, &mut mentions);
+    }
+    mentions
+}
+
+pub(crate) fn parse_mentions(text: &str, mentions: &mut Vec<Mention>) {
+    for captures in MENTION_REGEX.captures_iter(text) {
+        let mention = if let Some(id) = capture_id(&captures, 1) {
+            Mention::User(UserId::new(id))
+        } else if let Some(id) = capture_id(&captures, 2) {
+            Mention::Role(RoleId::new(id))
+        } else if let Some(id) = capture_id(&captures, 3) {
+            Mention::Channel(ChannelId::new(id))
+        } else if &captures[0] == "@everyone" {
+            Mention::Everyone
+        } else if &captures[0] == "@here" {
+            Mention::Here
+        } else {
+            // The ID doesn't fit in a snowflake.
+            continue;
+        };
+        if !mentions.contains(&mention) {
+            mentions.push(mention);
+        }
+    }
+}
+
+fn capture_id(captures: &Captures, group: usize) -> Option<u64> {
+    captures
+        .get(group)
+        .and_then(|id| id.as_str().parse().ok())
+        .filter(|id| *id != 0)
+}
+
+/// Keep the mentions the client's policy allows to ping. Clients without a policy may ping users,
+/// and @everyone/@here are never allowed.
+pub(crate) fn allowed_mentions(
+    policy: Option<&MentionPolicy>,
+    mentions: &[Mention],
+) -> Vec<Mention> {
+    mentions
+        .iter()
+        .filter(|mention| match mention {
+            Mention::User(_) => policy.is_none_or(|policy| policy.users),
+            Mention::Role(role) => policy.is_some_and(|policy| policy.roles.contains(&role.get())),
+            Mention::Channel(_) | Mention::Everyone | Mention::Here => false,
+        })
+        .copied()
+        .collect()
+}
+
+/// Message content that pings the mentions, as mentions inside embeds don't notify anyone.
+pub(crate) fn mention_content(mentions: &[Mention]) -> String {
+    let mut content = String::new();
+    for mention in mentions {
+        match mention {
+            Mention::User(user) => content += &format!("<@{user}> "),
+            Mention::Role(role) => content += &format!("<@&{role}> "),
+            Mention::Channel(_) | Mention::Everyone | Mention::Here => {}
+        }
+    }
+    content
+}
+
+/// Restrict discord to pinging exactly `mentions`, and the replied to user when `replied_user` is set.
+pub(crate) fn create_allowed_mentions(
+    mentions: &[Mention],
+    replied_user: bool,
+) -> CreateAllowedMentions {
+    let users = mentions.iter().filter_map(|mention| match mention {
+        Mention::User(user) => Some(*user),
+        _ => None,
+    });
+    let roles = mentions.iter().filter_map(|mention| match mention {
+        Mention::Role(role) => Some(*role),
+        _ => None,
+    });
+    CreateAllowedMentions::new()
+        .users(users)
+        .roles(roles)
+        .everyone(false)
+        .replied_user(replied_user)
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::id::{ChannelId, RoleId, UserId};
+
+    use crate::{
+        mentions::{Mention, allowed_mentions, extract_mentions, mention_content, parse_mentions},
+        messages::{EmbedContent, MentionPolicy, TextField},
+    };
+
+    #[test]
+    fn test_extract_mentions_empty() {
+        let e = EmbedContent::default();
+        let mentions = extract_mentions(&e);
+        assert!(mentions.is_empty());
+    }
+
+    #[test]
+    fn test_extract_mentions_title() {
+        let e = EmbedContent {
+            title: "<@12345678910> <@Everyone>".to_string(),
+            ..Default::default()
+        };
+        let mentions = extract_mentions(&e);
+        assert_eq!(vec![Mention::User(UserId::new(12345678910))], mentions);
+    }
+
+    #[test]
+    fn test_extract_mentions_description() {
+        let e = EmbedContent {
+            description: "<@12345678910> <@Everyone>".to_string(),
+            ..Default::default()
+        };
+        let mentions = extract_mentions(&e);
+        assert_eq!(vec![Mention::User(UserId::new(12345678910))], mentions);
+    }
+
+    #[test]
+    fn test_extract_mentions_fields() {
+        let e = EmbedContent {
+            title: "<@1>".to_string(),
+            textfield: vec![TextField {
+                title: "<@&2>".to_string(),
+                text: "<#3> <@!1>".to_string(),
+                inline: false,
+            }],
+            ..Default::default()
+        };
+        let mentions = extract_mentions(&e);
+        assert_eq!(
+            vec![
+                Mention::User(UserId::new(1)),
+                Mention::Role(RoleId::new(2)),
+                Mention::Channel(ChannelId::new(3)),
+            ],
+            mentions
+        );
+    }
+
+    #[test]
+    fn test_parse_mentions_everyone() {
+        let mut mentions = vec![];
+        parse_mentions("@everyone @here <@0>", &mut mentions);
+        assert_eq!(vec![Mention::Everyone, Mention::Here], mentions);
+    }
+
+    #[test]
+    fn test_allowed_mentions_default() {
+        let mentions = vec![
+            Mention::User(UserId::new(1)),
+            Mention::Role(RoleId::new(2)),
+            Mention::Everyone,
+        ];
+        let allowed = allowed_mentions(None, &mentions);
+        assert_eq!(vec![Mention::User(UserId::new(1))], allowed);
+        assert_eq!("<@1> ", mention_content(&allowed));
+    }
+
+    #[test]
+    fn test_allowed_mentions_policy() {
+        let mentions = vec![
+            Mention::User(UserId::new(1)),
+            Mention::Role(RoleId::new(2)),
+            Mention::Role(RoleId::new(3)),
+            Mention::Here,
+        ];
+        let policy = MentionPolicy {
+            users: false,
+            roles: vec![3],
+        };
+        let allowed = allowed_mentions(Some(&policy), &mentions);
+        assert_eq!(vec![Mention::Role(RoleId::new(3))], allowed);
+        assert_eq!("<@&3> ", mention_content(&allowed));
+    }
+}
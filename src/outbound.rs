@@ -0,0 +1,240 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use async_std::{
+    channel::{Receiver, Sender, TrySendError, bounded},
+    io::Write,
+};
+use async_tungstenite::tungstenite::Message;
+use byteorder::{ByteOrder, LittleEndian};
+use color_eyre::{eyre, eyre::eyre};
+use futures::AsyncWriteExt;
+use log::error;
+use prost::Message as _;
+use serde::Deserialize;
+
+use crate::{
+    messages::Request,
+    transport::{Socket, WebSocketWriter},
+};
+
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// What to do when a client doesn't read its messages as fast as they are queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Disconnect the client once its queue is full.
+    Disconnect,
+}
+
+/// Messages waiting to be written to a client by its writer task.
+pub(crate) struct OutboundQueue {
+    sender: Sender<Request>,
+    // Held so the oldest message can be dropped when the queue is full.
+    receiver: Receiver<Request>,
+    policy: SlowConsumerPolicy,
+    // Shut down to disconnect the client, None for gRPC clients.
+    socket: Option<Socket>,
+    dropped: AtomicU64,
+    max_depth: AtomicUsize,
+}
+
+impl OutboundQueue {
+    /// Returns the queue, and the receiver to pass to `write_loop`.
+    pub(crate) fn new(
+        capacity: usize,
+        policy: SlowConsumerPolicy,
+        socket: Option<Socket>,
+    ) -> (Self, Receiver<Request>) {
+        let (sender, receiver) = bounded(capacity);
+        let queue = OutboundQueue {
+            sender,
+            receiver: receiver.clone(),
+            policy,
+            socket,
+            dropped: AtomicU64::new(0),
+            max_depth: AtomicUsize::new(0),
+        };
+        (queue, receiver)
+    }
+
+    /// Queue a message without waiting. Fails if the queue was closed, or the client is too slow
+    /// and should be disconnected.
+    pub(crate) fn push(&self, request: Request) -> eyre::Result<()> {
+        let mut request = request;
+        loop {
+            match self.sender.try_send(request) {
+                Ok(()) => {
+                    self.max_depth
+                        .fetch_max(self.sender.len(), Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(TrySendError::Closed(_)) => return Err(eyre!("Connection is closed")),
+                Err(TrySendError::Full(rejected)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    match self.policy {
+                        SlowConsumerPolicy::DropOldest => {
+                            let _ = self.receiver.try_recv();
+                            request = rejected;
+                        }
+                        SlowConsumerPolicy::Disconnect => {
+                            self.disconnect();
+                            return Err(eyre!("Client is not reading its messages"));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stop accepting messages, the writer task exits once the queued ones are written.
+    pub(crate) fn close(&self) {
+        self.sender.close();
+    }
+
+    /// Stop accepting messages and shut down the socket, so that a writer blocked on a client
+    /// that isn't reading, and the reader, stop without writing the queued ones.
+    pub(crate) fn disconnect(&self) {
+        self.close();
+        if let Some(socket) = &self.socket {
+            socket.shutdown();
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    pub(crate) fn depth(&self) -> usize {
+        self.sender.len()
+    }
+
+    pub(crate) fn max_depth(&self) -> usize {
+        self.max_depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Where a connection's frames are written to.
+pub(crate) enum FrameWriter {
+    Stream(Box<dyn Write + Send + Unpin>),
+    WebSocket(WebSocketWriter),
+    // Feeds the gRPC response stream.
+    Grpc(Sender<Request>),
+}
+
+impl FrameWriter {
+    pub(crate) async fn write(&mut self, request: Request) -> eyre::Result<()> {
+        match self {
+            FrameWriter::Stream(stream) => write_frame(stream, &request.encode_to_vec()).await,
+            FrameWriter::WebSocket(websocket) => {
+                let message = Message::binary(request.encode_to_vec());
+                Ok(websocket.send(message).await?)
+            }
+            FrameWriter::Grpc(sender) => Ok(sender.send(request).await?),
+        }
+    }
+
+    // Lets TLS and WebSocket send their close notifications.
+    async fn close(&mut self) {
+        match self {
+            FrameWriter::Stream(stream) => {
+                let _ = stream.close().await;
+            }
+            FrameWriter::WebSocket(websocket) => {
+                let _ = websocket.close(None).await;
+            }
+            FrameWriter::Grpc(sender) => {
+                sender.close();
+            }
+        }
+    }
+}
+
+/// Write queued messages to the client until the queue is closed, then close the connection.
+/// `socket` is the socket underneath `writer`, shut down so the reader stops too.
+pub(crate) async fn write_loop(
+    mut writer: FrameWriter,
+    socket: Option<Socket>,
+    receiver: Receiver<Request>,
+) {
+    while let Ok(request) = receiver.recv().await {
+        if let Err(e) = writer.write(request).await {
+            error!("Failed to send message: {e}");
+            break;
+        }
+    }
+    writer.close().await;
+    if let Some(socket) = socket {
+        socket.shutdown();
+    }
+}
+
+pub(crate) async fn write_frame<W: Write + Unpin>(stream: &mut W, data: &[u8]) -> eyre::Result<()> {
+    let length_buf = &mut [0u8; 4];
+    LittleEndian::write_u32(length_buf, u32::try_from(data.len())?);
+
+    stream.write_all(length_buf).await?;
+    stream.write_all(data).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        messages::Request,
+        outbound::{OutboundQueue, SlowConsumerPolicy},
+    };
+
+    fn request(user: u64) -> Request {
+        Request {
+            user,
+            ..Default::default()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_push_drop_oldest() {
+        let (queue, receiver) = OutboundQueue::new(2, SlowConsumerPolicy::DropOldest, None);
+        queue.push(request(1)).unwrap();
+        queue.push(request(2)).unwrap();
+        queue.push(request(3)).unwrap();
+
+        assert_eq!(1, queue.dropped());
+        assert_eq!(2, queue.depth());
+        assert_eq!(request(2), receiver.recv().await.unwrap());
+        assert_eq!(request(3), receiver.recv().await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_push_disconnect() {
+        let (queue, receiver) = OutboundQueue::new(2, SlowConsumerPolicy::Disconnect, None);
+        queue.push(request(1)).unwrap();
+        queue.push(request(2)).unwrap();
+        assert!(queue.push(request(3)).is_err());
+        assert!(queue.push(request(4)).is_err());
+
+        // Without a socket to shut down, messages queued before the disconnect are left for the
+        // writer.
+        assert_eq!(request(1), receiver.recv().await.unwrap());
+        assert_eq!(request(2), receiver.recv().await.unwrap());
+        assert!(receiver.recv().await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_close() {
+        let (queue, receiver) = OutboundQueue::new(2, SlowConsumerPolicy::DropOldest, None);
+        queue.push(request(1)).unwrap();
+        queue.close();
+
+        assert!(queue.push(request(2)).is_err());
+        assert_eq!(request(1), receiver.recv().await.unwrap());
+        assert!(receiver.recv().await.is_err());
+        assert_eq!(1, queue.max_depth());
+    }
+}
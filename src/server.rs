@@ -1,102 +1,453 @@
-use std::{borrow::Cow, env, sync::Arc, time::SystemTime};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use async_std::{
-    io::{ReadExt, WriteExt},
-    net::{TcpListener, TcpStream},
+    future::timeout,
+    net::TcpListener,
     sync::{Mutex, RwLock},
+    task,
 };
-use byteorder::{ByteOrder, LittleEndian};
 use color_eyre::eyre;
 use csv::Writer;
 use futures::stream::StreamExt;
-use log::{debug, error, info};
+use futures_rustls::TlsAcceptor;
+use log::{debug, error, info, warn};
 use prost::Message;
-use regex::Regex;
 use serenity::{
-    all::{ActivityData, CreateAttachment, CreateEmbed, CreateEmbedAuthor, CreateMessage},
-    client::Context,
+    all::{
+        ActivityData,
+        ButtonStyle,
+        CommandInteraction,
+        ComponentInteraction,
+        ComponentInteractionDataKind,
+        CreateActionRow,
+        CreateAttachment,
+        CreateButton,
+        CreateEmbed,
+        CreateEmbedAuthor,
+        CreateEmbedFooter,
+        CreateInteractionResponse,
+        CreateInteractionResponseMessage,
+        CreateSelectMenu,
+        CreateSelectMenuKind,
+        CreateSelectMenuOption,
+        Member,
+        Message as DiscordMessage,
+        MessageUpdateEvent,
+        ReactionType,
+        Timestamp,
+        User,
+    },
     model::{
-        id::{ChannelId, UserId},
+        id::{ChannelId, GuildId, InteractionId, MessageId, RoleId, UserId},
         prelude::OnlineStatus,
     },
 };
 
 use crate::{
-    embedbuilder::{build_embeds, split_file},
+    commands::{DISCORD_MAX_COMMANDS, check_definitions, create_command, parse_arguments},
+    config::{ServerConfig, UnixConfig},
+    discord::{Discord, MessageEdit, OutgoingMessage},
+    embedbuilder::{
+        DISCORD_MAX_ACTION_ROWS,
+        DISCORD_MAX_BUTTONS,
+        DISCORD_MAX_CUSTOM_ID,
+        DISCORD_MAX_SELECT_OPTIONS,
+        build_embeds,
+        split_file,
+    },
+    error::{ShimError, to_client_error},
+    grpc::GrpcService,
+    handshake::{ClientInfo, handshake},
+    inbound::FrameReader,
+    mentions::{
+        allowed_mentions,
+        create_allowed_mentions,
+        extract_mentions,
+        mention_content,
+        parse_mentions,
+    },
+    messages,
     messages::{
+        Ack,
+        ActionRow,
+        Button,
+        ChannelStatus,
+        CommandDefinition,
         EmbedContent,
+        Error,
+        ErrorCode,
+        Feature,
+        Interaction,
+        MentionPolicy,
+        MessageInfo,
         ProtoFile,
         Request,
         Response,
-        request::Message::{Command, File},
+        SelectMenu,
+        ServerHello,
+        ServerStats,
+        SessionInfo,
+        SlashCommand,
+        request::Message::{
+            Ack as AckMessage,
+            Command,
+            Error as ErrorMessage,
+            File,
+            Hello as HelloMessage,
+            Interaction as InteractionMessage,
+            Session,
+            SlashCommand as SlashCommandMessage,
+        },
         response::Field,
     },
+    outbound::{FrameWriter, OutboundQueue, write_loop},
+    tls::acceptor,
+    transport::{BoxedConnection, Socket, Transport, bind_unix},
 };
 
+const PAIRING_CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+// Discord invalidates interaction tokens after 15 minutes.
+const INTERACTION_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
 #[derive(serde::Serialize)]
 struct Stats {
     ip: String,
+    // Name and version from the client's Hello, empty for clients that don't send one.
+    client: String,
     num_messages: u64,
     total_data: usize,
+    queue_depth: usize,
+    max_queue_depth: usize,
+    dropped_messages: u64,
 }
 
 struct DiscordSettings {
-    tcpstream: RwLock<TcpStream>,
-    channel: RwLock<ChannelId>,
+    peer_addr: String,
+    outbound: OutboundQueue,
+    // Set by the client's Hello, None for legacy clients that go straight to Settings.
+    client: Mutex<Option<ClientInfo>>,
+    // None until the client has paired with a channel.
+    channel: RwLock<Option<ChannelId>>,
     // Only relevant when self-hosting, global discordshim won't support presence anyway
     prefix: Mutex<String>,
     cycle_time: Mutex<i32>,
     enabled: Mutex<bool>,
     num_messages: Mutex<u64>,
     total_data: Mutex<usize>,
+    // Slash commands registered by the client, in the guild of its channel.
+    guild: Mutex<Option<GuildId>>,
+    commands: Mutex<Vec<CommandDefinition>>,
+    // None until the client sends a policy, see `allowed_mentions` for the default.
+    mentions: Mutex<Option<MentionPolicy>>,
+    // Issued once the client has bound a channel, lets it resume the session after reconnecting.
+    session_token: Mutex<Option<String>>,
+    // Requests kept while the client is disconnected, Some only during the grace period.
+    // A std mutex, as `send` isn't async.
+    pending: std::sync::Mutex<Option<PendingRequests>>,
+}
+
+/// Bounded buffer of the requests for a disconnected client, dropping the oldest when full.
+struct PendingRequests {
+    requests: VecDeque<Request>,
+    capacity: usize,
+}
+
+impl PendingRequests {
+    fn new(capacity: usize) -> Self {
+        PendingRequests {
+            requests: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, request: Request) {
+        if self.requests.len() >= self.capacity {
+            self.requests.pop_front();
+        }
+        self.requests.push_back(request);
+    }
 }
 
 impl DiscordSettings {
+    fn new(peer_addr: String, outbound: OutboundQueue) -> Self {
+        DiscordSettings {
+            peer_addr,
+            outbound,
+            client: Mutex::new(None),
+            channel: RwLock::new(None),
+            prefix: Mutex::new(String::new()),
+            cycle_time: Mutex::new(0),
+            enabled: Mutex::new(false),
+            num_messages: Mutex::new(0),
+            total_data: Mutex::new(0),
+            guild: Mutex::new(None),
+            commands: Mutex::new(Vec::new()),
+            mentions: Mutex::new(None),
+            session_token: Mutex::new(None),
+            pending: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Queue an encoded request for the client's writer task, disconnecting the client if it
+    /// can't keep up. Requests are buffered instead while the client's session is detached.
+    fn send(&self, request: Request) -> eyre::Result<()> {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.push(request);
+            return Ok(());
+        }
+        let result = self.outbound.push(request);
+        if let Err(e) = &result {
+            error!("Disconnecting {}: {e}", self.peer_addr);
+        }
+        result
+    }
+
     async fn get_stats(&self) -> Stats {
         Stats {
-            ip: self
-                .tcpstream
-                .read()
+            ip: self.peer_addr.clone(),
+            client: self
+                .client
+                .lock()
                 .await
-                .peer_addr()
-                .unwrap()
-                .to_string()
-                .clone(),
+                .as_ref()
+                .map_or(String::new(), |client| {
+                    format!("{} {}", client.name, client.version)
+                }),
             num_messages: *self.num_messages.lock().await,
             total_data: *self.total_data.lock().await,
+            queue_depth: self.outbound.depth(),
+            max_queue_depth: self.outbound.max_depth(),
+            dropped_messages: self.outbound.dropped(),
         }
     }
 }
 
+/// Clients keyed by the channel they are bound to, so routing a discord message doesn't have to
+/// look at every connection.
+struct ChannelIndex<T> {
+    channels: HashMap<ChannelId, Vec<Arc<T>>>,
+}
+
+impl<T> ChannelIndex<T> {
+    fn new() -> Self {
+        ChannelIndex {
+            channels: HashMap::new(),
+        }
+    }
+
+    fn get(&self, channel: ChannelId) -> Vec<Arc<T>> {
+        self.channels.get(&channel).cloned().unwrap_or_default()
+    }
+
+    fn insert(&mut self, channel: ChannelId, client: &Arc<T>) {
+        let clients = self.channels.entry(channel).or_default();
+        if !clients.iter().any(|c| Arc::ptr_eq(c, client)) {
+            clients.push(client.clone());
+        }
+    }
+
+    fn remove(&mut self, channel: ChannelId, client: &Arc<T>) {
+        if let Some(clients) = self.channels.get_mut(&channel) {
+            clients.retain(|c| !Arc::ptr_eq(c, client));
+            if clients.is_empty() {
+                self.channels.remove(&channel);
+            }
+        }
+    }
+}
+
+struct PairingCode {
+    channel: ChannelId,
+    issued: SystemTime,
+}
+
+impl PairingCode {
+    fn expired(&self, now: SystemTime) -> bool {
+        now.duration_since(self.issued)
+            .map_or(true, |age| age >= PAIRING_CODE_LIFETIME)
+    }
+}
+
+struct PendingInteraction {
+    channel: ChannelId,
+    token: String,
+    received: SystemTime,
+}
+
+impl PendingInteraction {
+    fn expired(&self, now: SystemTime) -> bool {
+        now.duration_since(self.received)
+            .map_or(true, |age| age >= INTERACTION_LIFETIME)
+    }
+}
+
 pub struct Server {
     clients: Arc<Mutex<Vec<Arc<DiscordSettings>>>>,
+    // Kept in sync with `DiscordSettings.channel`, used to route discord messages to clients.
+    channels: RwLock<ChannelIndex<DiscordSettings>>,
     last_presense_update: Mutex<SystemTime>,
+    // Channels that clients may bind to without a pairing code, e.g. the health check channel.
+    trusted_channels: Vec<ChannelId>,
+    pairing_codes: Mutex<HashMap<String, PairingCode>>,
+    interactions: Mutex<HashMap<u64, PendingInteraction>>,
+    // Clients that disconnected within the grace period, keyed by session token.
+    sessions: Mutex<HashMap<String, Arc<DiscordSettings>>>,
+    config: ServerConfig,
 }
 
 impl Default for Server {
     fn default() -> Self {
-        Self::new()
+        Self::new(ServerConfig::default())
     }
 }
 
 impl Server {
-    pub fn new() -> Server {
+    pub fn new(config: ServerConfig) -> Server {
         Server {
             clients: Arc::new(Mutex::new(Vec::new())),
+            channels: RwLock::new(ChannelIndex::new()),
             last_presense_update: Mutex::new(SystemTime::UNIX_EPOCH),
+            trusted_channels: config
+                .trusted_channels
+                .iter()
+                .map(|&id| ChannelId::new(id))
+                .collect(),
+            pairing_codes: Mutex::new(HashMap::new()),
+            interactions: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            config,
         }
     }
 
-    pub async fn run(&self, ctx: Arc<Context>) {
-        debug!("Starting TCP listener");
-        let listener = TcpListener::bind("0.0.0.0:23416")
-            .await
-            .expect("Failed to bind");
+    /// Issue a one-time code that lets a client bind to `channel` via `Settings.pairing_code`.
+    pub async fn create_pairing_code(&self, channel: ChannelId) -> String {
+        let code = uuid::Uuid::new_v4().simple().to_string()[..8].to_uppercase();
+        let now = SystemTime::now();
+
+        let mut codes = self.pairing_codes.lock().await;
+        codes.retain(|_, pairing| !pairing.expired(now));
+        codes.insert(
+            code.clone(),
+            PairingCode {
+                channel,
+                issued: now,
+            },
+        );
+        code
+    }
+
+    async fn consume_pairing_code(&self, code: &str, channel: ChannelId) -> bool {
+        // Codes are single use, so burn it even if it was presented for the wrong channel.
+        match self.pairing_codes.lock().await.remove(code) {
+            Some(pairing) => pairing.channel == channel && !pairing.expired(SystemTime::now()),
+            None => false,
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, discord: Arc<dyn Discord>) {
+        let acceptor = self
+            .config
+            .tls
+            .as_ref()
+            .map(|tls| acceptor(tls).expect("Failed to load TLS certificate"));
+
+        let plaintext = async {
+            if self.config.plaintext {
+                self.listen(
+                    &self.config.bind,
+                    Transport::LengthPrefixed,
+                    None,
+                    discord.clone(),
+                )
+                .await;
+            }
+        };
+        let tls = async {
+            if let (Some(tls), Some(acceptor)) = (&self.config.tls, &acceptor) {
+                self.listen(
+                    &tls.bind,
+                    Transport::LengthPrefixed,
+                    Some(acceptor.clone()),
+                    discord.clone(),
+                )
+                .await;
+            }
+        };
+        let websocket = async {
+            if let Some(websocket) = &self.config.websocket {
+                let acceptor = acceptor.clone().filter(|_| websocket.tls);
+                self.listen(
+                    &websocket.bind,
+                    Transport::WebSocket,
+                    acceptor,
+                    discord.clone(),
+                )
+                .await;
+            }
+        };
+        let grpc = async {
+            if let Some(grpc) = &self.config.grpc {
+                debug!("Starting gRPC listener on {}", grpc.bind);
+                let service = GrpcService::new(
+                    self.clone(),
+                    discord.clone(),
+                    self.config.limits.max_frame_size,
+                    grpc.status,
+                );
+                tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve(grpc.bind.parse().expect("Invalid gRPC bind address"))
+                    .await
+                    .expect("Failed to serve gRPC");
+            }
+        };
+        let unix = async {
+            if let Some(unix) = &self.config.unix {
+                self.listen_unix(unix, discord.clone()).await;
+            }
+        };
+        futures::join!(plaintext, tls, websocket, grpc, unix);
+    }
+
+    async fn listen(
+        &self,
+        bind: &str,
+        transport: Transport,
+        tls: Option<TlsAcceptor>,
+        discord: Arc<dyn Discord>,
+    ) {
+        debug!(
+            "Starting {transport:?} listener on {bind}{}",
+            if tls.is_some() { " with TLS" } else { "" }
+        );
+        let listener = TcpListener::bind(bind).await.expect("Failed to bind");
+        self.accept(listener, transport, tls, discord).await;
+    }
+
+    /// Serve length-prefixed clients on a listener that is already bound, e.g. to an ephemeral
+    /// port in tests. `run` serves the listeners from the config instead.
+    pub async fn serve(&self, listener: TcpListener, discord: Arc<dyn Discord>) {
+        self.accept(listener, Transport::LengthPrefixed, None, discord)
+            .await;
+    }
+
+    async fn accept(
+        &self,
+        listener: TcpListener,
+        transport: Transport,
+        tls: Option<TlsAcceptor>,
+        discord: Arc<dyn Discord>,
+    ) {
         listener
             .incoming()
             .for_each_concurrent(None, |tcpstream| {
-                let ctx2 = ctx.clone();
-                let clients2 = self.clients.clone();
+                let discord2 = discord.clone();
+                let tls = tls.clone();
                 async move {
                     let stream = match tcpstream {
                         Err(e) => {
@@ -108,50 +459,248 @@ impl Server {
 
                     let peer_addr = stream.peer_addr().unwrap();
                     info!("Received connection from: {}", peer_addr);
+                    // Frames are written as a length and a body, which Nagle's algorithm would
+                    // hold back until the client acks the length.
+                    if let Err(e) = stream.set_nodelay(true) {
+                        warn!("Failed to set TCP_NODELAY for {peer_addr}: {e}");
+                    }
 
-                    let settings = Arc::new(DiscordSettings {
-                        tcpstream: RwLock::new(stream.clone()),
-                        channel: RwLock::new(ChannelId::default()),
-                        prefix: Mutex::new(String::new()),
-                        cycle_time: Mutex::new(0),
-                        enabled: Mutex::new(false),
-                        num_messages: Mutex::new(0),
-                        total_data: Mutex::new(0),
-                    });
-
-                    clients2.lock().await.insert(0, settings.clone());
-
-                    let num_servers = clients2.lock().await.len();
-                    self.update_presence(ctx2.clone(), num_servers).await;
-
-                    let _loop_res = self
-                        .connection_loop(stream, settings.clone(), ctx2.clone())
-                        .await;
-                    clients2
-                        .lock()
-                        .await
-                        .retain(|item| !Arc::<DiscordSettings>::ptr_eq(item, &settings));
-
-                    let num_servers = clients2.lock().await.len();
-                    self.update_presence(ctx2, num_servers).await;
+                    // Don't let a client hold the socket open without finishing the handshakes.
+                    let limits = &self.config.limits;
+                    let connection: BoxedConnection = match tls {
+                        None => Box::new(stream.clone()),
+                        Some(acceptor) => {
+                            match timeout(limits.frame_timeout, acceptor.accept(stream.clone()))
+                                .await
+                            {
+                                Ok(Ok(tls_stream)) => Box::new(tls_stream),
+                                Ok(Err(e)) => {
+                                    warn!("TLS handshake with {peer_addr} failed: {e}");
+                                    return;
+                                }
+                                Err(_) => {
+                                    warn!("TLS handshake with {peer_addr} timed out");
+                                    return;
+                                }
+                            }
+                        }
+                    };
+                    let (reader, writer) = match transport.open(connection, limits).await {
+                        Ok(halves) => halves,
+                        Err(e) => {
+                            warn!("Failed to open {transport:?} connection with {peer_addr}: {e}");
+                            return;
+                        }
+                    };
 
+                    self.serve_client(
+                        Some(Socket::Tcp(stream)),
+                        peer_addr.to_string(),
+                        reader,
+                        writer,
+                        discord2,
+                    )
+                    .await;
                     info!("Dropped connection from: {}", peer_addr);
                 }
             })
             .await;
     }
 
-    async fn update_presence(&self, ctx: Arc<Context>, num_servers: usize) {
+    async fn listen_unix(&self, config: &UnixConfig, discord: Arc<dyn Discord>) {
+        debug!("Starting Unix listener on {}", config.path.display());
+        let listener = bind_unix(&config.path, config.mode)
+            .await
+            .expect("Failed to bind Unix socket");
+        // Unix peers are usually unnamed, so clients are known by the socket path.
+        let peer_addr = format!("unix:{}", config.path.display());
+        listener
+            .incoming()
+            .for_each_concurrent(None, |unixstream| {
+                let discord2 = discord.clone();
+                let peer_addr = peer_addr.clone();
+                async move {
+                    let stream = match unixstream {
+                        Err(e) => {
+                            error!("Unix stream error {:?}", e);
+                            return;
+                        }
+                        Ok(s) => s,
+                    };
+                    info!("Received connection on: {}", peer_addr);
+
+                    let limits = &self.config.limits;
+                    let (reader, writer) = match Transport::LengthPrefixed
+                        .open(Box::new(stream.clone()), limits)
+                        .await
+                    {
+                        Ok(halves) => halves,
+                        Err(e) => {
+                            warn!("Failed to open connection on {peer_addr}: {e}");
+                            return;
+                        }
+                    };
+
+                    self.serve_client(
+                        Some(Socket::Unix(stream)),
+                        peer_addr.clone(),
+                        reader,
+                        writer,
+                        discord2,
+                    )
+                    .await;
+                    info!("Dropped connection on: {}", peer_addr);
+                }
+            })
+            .await;
+    }
+
+    pub(crate) async fn serve_client(
+        &self,
+        socket: Option<Socket>,
+        peer_addr: String,
+        reader: FrameReader,
+        writer: FrameWriter,
+        discord: Arc<dyn Discord>,
+    ) {
+        let (outbound, receiver) = OutboundQueue::new(
+            self.config.queue_capacity,
+            self.config.slow_consumer,
+            socket.clone(),
+        );
+        let mut write_task = task::spawn(write_loop(writer, socket, receiver));
+
+        let settings = Arc::new(DiscordSettings::new(peer_addr, outbound));
+
+        self.clients.lock().await.insert(0, settings.clone());
+
+        let num_servers = self.clients.lock().await.len();
+        self.update_presence(discord.clone(), num_servers).await;
+
+        let _loop_res = self
+            .connection_loop(reader, settings.clone(), discord.clone())
+            .await;
+        settings.outbound.close();
+        // A client that stopped reading would keep the writer blocked forever.
+        if timeout(self.config.limits.frame_timeout, &mut write_task)
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} didn't read its remaining messages, disconnecting",
+                settings.peer_addr
+            );
+            settings.outbound.disconnect();
+            write_task.cancel().await;
+        }
+
+        // Keep the session around for the client to resume, unless it didn't get that far.
+        if let Some(token) = self.detach_session(&settings).await {
+            task::sleep(self.config.session_grace_period).await;
+            if !self.expire_session(&settings, &token).await {
+                // Resumed, the new connection took over the channel and commands.
+                return;
+            }
+            info!("Session of {} expired", settings.peer_addr);
+        }
+        self.unbind_channel(&settings).await;
+        self.clients
+            .lock()
+            .await
+            .retain(|item| !Arc::<DiscordSettings>::ptr_eq(item, &settings));
+
+        // Drop the client's slash commands, unless another client in the guild registered them too.
+        if let Some(guild) = *settings.guild.lock().await
+            && let Err(e) = self.sync_commands(discord.as_ref(), guild).await
+        {
+            error!("Failed to update commands for guild {guild}: {e}");
+        }
+
+        let num_servers = self.clients.lock().await.len();
+        self.update_presence(discord, num_servers).await;
+    }
+
+    /// Start buffering requests for a disconnected client. Returns the session token if the
+    /// session can be resumed.
+    async fn detach_session(&self, settings: &Arc<DiscordSettings>) -> Option<String> {
+        if self.config.session_grace_period.is_zero() {
+            return None;
+        }
+        let token = settings.session_token.lock().await.clone()?;
+        *settings.pending.lock().unwrap() = Some(PendingRequests::new(self.config.session_buffer));
+        self.sessions
+            .lock()
+            .await
+            .insert(token.clone(), settings.clone());
+        info!(
+            "Keeping session of {} for {:?}",
+            settings.peer_addr, self.config.session_grace_period
+        );
+        Some(token)
+    }
+
+    /// Drop the session `settings` detached once its grace period is over. Returns false if it was
+    /// resumed, in which case the token may have been detached again by the client that resumed it.
+    async fn expire_session(&self, settings: &Arc<DiscordSettings>, token: &str) -> bool {
+        let mut sessions = self.sessions.lock().await;
+        match sessions.get(token) {
+            Some(detached) if Arc::ptr_eq(detached, settings) => {
+                sessions.remove(token);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Move a detached session onto a new client, returning the requests buffered for it.
+    /// Returns None if the token is unknown or has expired.
+    async fn resume_session(
+        &self,
+        settings: &Arc<DiscordSettings>,
+        token: &str,
+    ) -> Option<Vec<Request>> {
+        let old = self.sessions.lock().await.remove(token)?;
+
+        *settings.prefix.lock().await = old.prefix.lock().await.clone();
+        *settings.cycle_time.lock().await = *old.cycle_time.lock().await;
+        *settings.enabled.lock().await = *old.enabled.lock().await;
+        *settings.mentions.lock().await = old.mentions.lock().await.clone();
+        *settings.guild.lock().await = *old.guild.lock().await;
+        *settings.commands.lock().await = old.commands.lock().await.clone();
+        *settings.session_token.lock().await = Some(token.to_string());
+
+        // Swap the clients in one go, so requests for the channel aren't sent to both.
+        {
+            let mut channels = self.channels.write().await;
+            if let Some(channel) = old.channel.write().await.take() {
+                channels.remove(channel, &old);
+                channels.insert(channel, settings);
+                *settings.channel.write().await = Some(channel);
+            }
+        }
+        self.clients
+            .lock()
+            .await
+            .retain(|item| !Arc::<DiscordSettings>::ptr_eq(item, &old));
+
+        let pending = old.pending.lock().unwrap().take();
+        info!(
+            "{} resumed the session of {}",
+            settings.peer_addr, old.peer_addr
+        );
+        Some(pending.map_or(vec![], |pending| pending.requests.into()))
+    }
+
+    async fn update_presence(&self, discord: Arc<dyn Discord>, num_servers: usize) {
         let mut last_update = self.last_presense_update.lock().await;
         let now = SystemTime::now();
-        if now.duration_since(*last_update).unwrap().as_secs() < 60 {
+        if now.duration_since(*last_update).unwrap() < self.config.presence_interval {
             return;
         }
 
-        let cloud = env::var("CLOUD_SERVER");
-        if cloud.is_ok() {
+        if self.config.cloud {
             let presence = format!("to {num_servers} instances");
-            ctx.set_presence(
+            discord.set_presence(
                 Some(ActivityData::streaming(presence, "https://octoprint.org").unwrap()),
                 OnlineStatus::Online,
             );
@@ -162,23 +711,75 @@ impl Server {
 
     async fn connection_loop(
         &self,
-        mut stream: TcpStream,
+        mut reader: FrameReader,
         settings: Arc<DiscordSettings>,
-        ctx: Arc<Context>,
+        discord: Arc<dyn Discord>,
     ) -> eyre::Result<()> {
+        let mut first = true;
         loop {
-            let length_buf = &mut [0u8; 4];
-            stream.read_exact(length_buf).await?;
-            let length = LittleEndian::read_u32(length_buf) as usize;
-            debug!("Incoming response, {length} bytes long.");
-
-            let mut buf = vec![0u8; length];
-            stream.read_exact(&mut buf).await?;
+            // Closed when the client is disconnected for being too slow, which doesn't
+            // interrupt reading from a gRPC stream.
+            if settings.outbound.is_closed() {
+                return Ok(());
+            }
+            let response = match reader.read(&self.config.limits).await {
+                Ok(Ok(response)) => response,
+                Err(e) => {
+                    if e.downcast_ref::<ShimError>().is_some() {
+                        warn!("Disconnecting {}: {e}", settings.peer_addr);
+                        settings.send(to_client_error(0, &e).into())?;
+                    }
+                    return Err(e);
+                }
+                Ok(Err(e)) => {
+                    let error =
+                        ShimError::new(ErrorCode::MalformedMessage, format!("Bad message: {e}"));
+                    settings.send(to_client_error(0, &error.into()).into())?;
+                    continue;
+                }
+            };
 
-            let response = Response::decode(buf.as_slice())?;
+            // Only the first message may be a Hello, legacy clients start with Settings instead.
+            if std::mem::take(&mut first)
+                && let Some(Field::Hello(hello)) = response.field
+            {
+                match handshake(hello) {
+                    Ok((reply, client)) => {
+                        info!(
+                            "{} is {} {}",
+                            settings.peer_addr, client.name, client.version
+                        );
+                        *settings.client.lock().await = Some(client);
+                        settings.send(reply.into())?;
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Disconnecting {}: {e}", settings.peer_addr);
+                        settings.send(to_client_error(response.id, &e).into())?;
+                        return Err(e);
+                    }
+                }
+            }
 
-            self.handle_task(settings.clone(), response, ctx.clone())
-                .await?;
+            let id = response.id;
+            match self
+                .handle_task(settings.clone(), response, discord.clone())
+                .await
+            {
+                Ok(message_ids) => {
+                    if id != 0 {
+                        let ack = Ack {
+                            id,
+                            message_ids: message_ids.iter().map(|m| m.get()).collect(),
+                        };
+                        settings.send(ack.into())?;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to handle message {id}: {e}");
+                    settings.send(to_client_error(id, &e).into())?;
+                }
+            }
         }
     }
 
@@ -186,165 +787,636 @@ impl Server {
         &self,
         settings: Arc<DiscordSettings>,
         response: Response,
-        ctx: Arc<Context>,
-    ) -> eyre::Result<()> {
+        discord: Arc<dyn Discord>,
+    ) -> eyre::Result<Vec<MessageId>> {
         *settings.num_messages.lock().await += 1;
         *settings.total_data.lock().await += response.encoded_len();
+        let channel = *settings.channel.read().await;
+        let reply_to = response.reply_to;
+        let reply_mention = response.reply_mention;
+        let policy = settings.mentions.lock().await.clone();
         match response.field {
-            None => Ok(()),
+            None => Ok(vec![]),
             Some(Field::File(protofile)) => {
+                let Some(channel) = channel else {
+                    return Err(not_paired());
+                };
                 let filename = protofile.filename.clone();
                 let filedata = protofile.data.as_slice();
-                let files = split_file(filename, filedata);
-                for file in files {
-                    let filename = file.1.filename.clone();
-                    let file_builder =
-                        CreateMessage::new().add_file(CreateAttachment::bytes(file.0, filename));
-                    settings
-                        .channel
-                        .read()
-                        .await
-                        .send_files(&ctx, vec![file.1], file_builder)
-                        .await?;
+                let files = split_file(filename, filedata, &self.config.attachments);
+                let mut message_ids = vec![];
+                for (i, file) in files.into_iter().enumerate() {
+                    let message = OutgoingMessage {
+                        attachments: vec![file.1],
+                        allowed_mentions: create_allowed_mentions(&[], reply_mention),
+                        reply_to: reply_reference(i, reply_to),
+                        ..Default::default()
+                    };
+                    message_ids.push(discord.send_message(channel, message).await?);
                 }
-                Ok(())
+                Ok(message_ids)
             }
 
             Some(Field::Embed(response_embed)) => {
-                let embeds = build_embeds(response_embed);
-                for e in embeds {
-                    let mentions = extract_mentions(&e);
-
-                    if e.snapshot.is_some() {
-                        let snapshot = e.snapshot.clone().unwrap();
-                        let filename_url = format!("attachment://{}", snapshot.filename);
-                        let filedata = snapshot.data.as_slice();
-                        let files = vec![CreateAttachment::bytes(
-                            Cow::from(filedata),
-                            snapshot.filename,
-                        )];
-                        let mut embed = CreateEmbed::new()
-                            .title(e.title)
-                            .description(e.description)
-                            .color(e.color)
-                            .author(CreateEmbedAuthor::new(e.author))
-                            .image(filename_url.clone());
-                        for field in e.textfield {
-                            embed = embed.field(field.title, field.text, field.inline);
-                        }
-                        let message = CreateMessage::new().embed(embed).content(mentions);
-                        settings
-                            .channel
-                            .read()
-                            .await
-                            .send_files(&ctx, files, message)
-                            .await?;
-                    } else {
-                        let mut embed = CreateEmbed::new()
-                            .title(e.title)
-                            .description(e.description)
-                            .color(e.color)
-                            .author(CreateEmbedAuthor::new(e.author));
-                        for field in e.textfield {
-                            embed = embed.field(field.title, field.text, field.inline);
+                let Some(channel) = channel else {
+                    return Err(not_paired());
+                };
+                // Build the components of every part first, so an invalid one fails before any part
+                // is posted.
+                let parts = build_embeds(response_embed)
+                    .into_iter()
+                    .map(|mut e| {
+                        let components = create_components(std::mem::take(&mut e.components))?;
+                        Ok((e, components))
+                    })
+                    .collect::<eyre::Result<Vec<_>>>()?;
+                let mut message_ids = vec![];
+                for (i, (e, components)) in parts.into_iter().enumerate() {
+                    let mentions = allowed_mentions(policy.as_ref(), &extract_mentions(&e));
+                    let (embed, attachments) = create_embed(e);
+
+                    let message = OutgoingMessage {
+                        content: mention_content(&mentions),
+                        embed: Some(embed),
+                        attachments,
+                        components,
+                        allowed_mentions: create_allowed_mentions(&mentions, reply_mention),
+                        reply_to: reply_reference(i, reply_to),
+                    };
+                    message_ids.push(discord.send_message(channel, message).await?);
+                }
+                Ok(message_ids)
+            }
+
+            Some(Field::Edit(edit)) => {
+                let Some(channel) = channel else {
+                    return Err(not_paired());
+                };
+                let message_id = to_message_id(edit.message_id)?;
+
+                let mut message_edit = MessageEdit::default();
+                if let Some(embed_content) = edit.embed {
+                    let mut embeds = build_embeds(embed_content);
+                    if embeds.len() != 1 {
+                        return Err(ShimError::new(
+                            ErrorCode::PayloadTooLarge,
+                            "Edited embed must fit in a single message",
+                        )
+                        .into());
+                    }
+                    let mut e = embeds.remove(0);
+                    let mentions = allowed_mentions(policy.as_ref(), &extract_mentions(&e));
+                    let components = create_components(std::mem::take(&mut e.components))?;
+                    let (embed, attachments) = create_embed(e);
+
+                    message_edit = MessageEdit {
+                        content: Some(mention_content(&mentions)),
+                        embed: Some(embed),
+                        components: Some(components),
+                        allowed_mentions: Some(create_allowed_mentions(&mentions, false)),
+                        attachments,
+                    };
+                }
+                if let Some(file) = edit.file {
+                    if file.data.len() >= self.config.attachments.max_attachment_size {
+                        return Err(ShimError::new(
+                            ErrorCode::PayloadTooLarge,
+                            "Edited attachment must fit in a single message",
+                        )
+                        .into());
+                    }
+                    message_edit
+                        .attachments
+                        .push(CreateAttachment::bytes(file.data, file.filename));
+                }
+
+                let message_id = discord
+                    .edit_message(channel, message_id, message_edit)
+                    .await?;
+                Ok(vec![message_id])
+            }
+
+            Some(Field::Delete(delete)) => {
+                let Some(channel) = channel else {
+                    return Err(not_paired());
+                };
+                let message_id = to_message_id(delete.message_id)?;
+
+                // The bot may be able to manage other users' messages, only allow deleting its own.
+                let author = discord.message_author(channel, message_id).await?;
+                if author != discord.current_user() {
+                    return Err(ShimError::new(
+                        ErrorCode::MissingAccess,
+                        format!("Message {message_id} was not sent by the shim"),
+                    )
+                    .into());
+                }
+                discord.delete_message(channel, message_id).await?;
+                Ok(vec![])
+            }
+
+            Some(Field::InteractionReply(reply)) => {
+                let Some(channel) = channel else {
+                    return Err(not_paired());
+                };
+                let token = self
+                    .interaction_token(reply.interaction_id, channel)
+                    .await?;
+
+                let mut mentions = vec![];
+                parse_mentions(&reply.content, &mut mentions);
+                let mut content = reply.content;
+
+                let mut message = OutgoingMessage::default();
+                if let Some(embed_content) = reply.embed {
+                    let mut embeds = build_embeds(embed_content);
+                    if embeds.len() != 1 {
+                        return Err(ShimError::new(
+                            ErrorCode::PayloadTooLarge,
+                            "Reply embed must fit in a single message",
+                        )
+                        .into());
+                    }
+                    let mut e = embeds.remove(0);
+                    let embed_mentions = allowed_mentions(policy.as_ref(), &extract_mentions(&e));
+                    content += &mention_content(&embed_mentions);
+                    for mention in embed_mentions {
+                        if !mentions.contains(&mention) {
+                            mentions.push(mention);
                         }
-                        let message = CreateMessage::new().embed(embed).content(mentions);
-
-                        settings
-                            .channel
-                            .read()
-                            .await
-                            .send_message(&ctx, message)
-                            .await?;
                     }
+                    let components = create_components(std::mem::take(&mut e.components))?;
+                    let (embed, attachments) = create_embed(e);
+
+                    message.embed = Some(embed);
+                    message.components = components;
+                    message.attachments = attachments;
+                }
+                let mentions = allowed_mentions(policy.as_ref(), &mentions);
+                message.content = content;
+                message.allowed_mentions = create_allowed_mentions(&mentions, false);
+
+                let message_id = discord
+                    .send_followup(&token, message, reply.ephemeral)
+                    .await?;
+                Ok(vec![message_id])
+            }
+
+            Some(Field::RegisterCommands(register)) => {
+                let Some(channel) = channel else {
+                    return Err(not_paired());
+                };
+                let guild = discord.channel_guild(channel).await?.ok_or_else(|| {
+                    ShimError::new(
+                        ErrorCode::NotAGuildChannel,
+                        format!("Channel {channel} is not in a guild"),
+                    )
+                })?;
+
+                check_definitions(&register.commands)?;
+                self.check_commands(&settings, guild, &register.commands)
+                    .await?;
+                let guild_before = settings.guild.lock().await.replace(guild);
+                let commands_before =
+                    std::mem::replace(&mut *settings.commands.lock().await, register.commands);
+                if let Err(e) = self.sync_commands(discord.as_ref(), guild).await {
+                    // Keep the commands discord accepted, or every later sync for the guild fails.
+                    *settings.guild.lock().await = guild_before;
+                    *settings.commands.lock().await = commands_before;
+                    return Err(e);
                 }
-                Ok(())
+                Ok(vec![])
             }
 
             Some(Field::Presence(presence)) => {
-                let cloud = env::var("CLOUD_SERVER");
-                if cloud.is_err() {
+                if !self.config.cloud {
                     let activity = ActivityData::playing(presence.presence);
-                    ctx.shard.set_presence(Some(activity), OnlineStatus::Online);
+                    discord.set_presence(Some(activity), OnlineStatus::Online);
                 }
-                Ok(())
+                Ok(vec![])
             }
 
+            Some(Field::Hello(_)) => Err(ShimError::new(
+                ErrorCode::MalformedMessage,
+                "Hello must be the first message",
+            )
+            .into()),
+
             Some(Field::Settings(new_settings)) => {
-                *settings.channel.write().await = ChannelId::from(new_settings.channel_id);
+                if new_settings.channel_id == 0 {
+                    return Err(not_paired());
+                }
+                // Only a client without a session of its own can resume one. The buffered
+                // requests are replayed even if the rest of the settings are rejected.
+                if !new_settings.session_token.is_empty()
+                    && settings.session_token.lock().await.is_none()
+                    && let Some(pending) = self
+                        .resume_session(&settings, &new_settings.session_token)
+                        .await
+                {
+                    let session = SessionInfo {
+                        token: new_settings.session_token.clone(),
+                        resumed: true,
+                        replayed: pending.len() as u32,
+                    };
+                    settings.send(session.into())?;
+                    for request in pending {
+                        settings.send(request)?;
+                    }
+                }
+                let channel = *settings.channel.read().await;
+                let new_channel = ChannelId::new(new_settings.channel_id);
+                if channel != Some(new_channel)
+                    && !self.trusted_channels.contains(&new_channel)
+                    && !self
+                        .consume_pairing_code(&new_settings.pairing_code, new_channel)
+                        .await
+                {
+                    warn!(
+                        "Rejected pairing with channel {new_channel} from {}",
+                        settings.peer_addr
+                    );
+                    return Err(ShimError::new(
+                        ErrorCode::InvalidPairingCode,
+                        format!(
+                            "Pairing code is invalid or expired, run /pair in channel {new_channel} to get a new one"
+                        ),
+                    )
+                    .into());
+                }
+                self.bind_channel(&settings, new_channel).await;
                 *settings.prefix.lock().await = new_settings.command_prefix;
                 *settings.cycle_time.lock().await = new_settings.cycle_time;
                 *settings.enabled.lock().await = new_settings.presence_enabled;
-                Ok(())
+                *settings.mentions.lock().await = new_settings.mentions;
+
+                let supports_sessions = settings
+                    .client
+                    .lock()
+                    .await
+                    .as_ref()
+                    .is_some_and(|client| client.supports(Feature::Sessions));
+                let mut session_token = settings.session_token.lock().await;
+                if supports_sessions && session_token.is_none() {
+                    let token = uuid::Uuid::new_v4().simple().to_string();
+                    *session_token = Some(token.clone());
+                    drop(session_token);
+                    let session = SessionInfo {
+                        token,
+                        ..Default::default()
+                    };
+                    settings.send(session.into())?;
+                }
+                Ok(vec![])
             }
         }
     }
 
+    async fn bind_channel(&self, settings: &Arc<DiscordSettings>, channel: ChannelId) {
+        // Hold the index lock while updating the client, so the two can't disagree.
+        let mut channels = self.channels.write().await;
+        let mut current = settings.channel.write().await;
+        if let Some(old) = *current {
+            channels.remove(old, settings);
+        }
+        channels.insert(channel, settings);
+        *current = Some(channel);
+    }
+
+    async fn unbind_channel(&self, settings: &Arc<DiscordSettings>) {
+        let mut channels = self.channels.write().await;
+        if let Some(old) = settings.channel.write().await.take() {
+            channels.remove(old, settings);
+        }
+    }
+
+    async fn clients_in(&self, channel: ChannelId) -> Vec<Arc<DiscordSettings>> {
+        self.channels.read().await.get(channel)
+    }
+
+    /// Forward a message to the clients bound to `channel` whose prefix it starts with, or that
+    /// address the bot by mentioning it first. The prefix or mention is stripped.
     pub async fn send_command(
         &self,
         channel: ChannelId,
         user: UserId,
+        bot: UserId,
         command: String,
+        info: MessageInfo,
     ) -> eyre::Result<()> {
+        let mut found = 0;
+        for client in self.clients_in(channel).await {
+            let Some(stripped) = strip_prefix(&command, &client.prefix.lock().await, bot) else {
+                continue;
+            };
+
+            let request = Request {
+                user: user.get(),
+                info: Some(info.clone()),
+                message: Some(Command(stripped.to_string())),
+            };
+            if client.send(request).is_err() {
+                continue;
+            }
+            found += 1;
+        }
+        info!("Sent command to {found} clients");
+        Ok(())
+    }
+
+    async fn _send_data(&self, channel: ChannelId, request: Request) -> eyre::Result<()> {
+        let mut found = 0;
+        for client in self.clients_in(channel).await {
+            if client.send(request.clone()).is_err() {
+                continue;
+            }
+            found += 1;
+        }
+        info!("Sent message to {found} clients");
+        Ok(())
+    }
+
+    /// Acknowledge a component interaction and forward it to the clients bound to its channel.
+    pub async fn send_interaction(&self, discord: &dyn Discord, interaction: ComponentInteraction) {
+        // Nobody would follow up on a deferred interaction, so tell the user straight away.
+        if self.clients_in(interaction.channel_id).await.is_empty() {
+            let message = CreateInteractionResponseMessage::new()
+                .content("No client is connected to this channel.")
+                .ephemeral(true);
+            if let Err(e) = discord
+                .respond_to_interaction(
+                    interaction.id,
+                    &interaction.token,
+                    CreateInteractionResponse::Message(message),
+                )
+                .await
+            {
+                error!("Failed to respond to interaction {}: {e}", interaction.id);
+            }
+            return;
+        }
+
+        // Deferring keeps the interaction alive, the client replies later with a followup.
+        let acknowledge = CreateInteractionResponse::Acknowledge;
+        if let Err(e) = discord
+            .respond_to_interaction(interaction.id, &interaction.token, acknowledge)
+            .await
+        {
+            error!("Failed to defer interaction {}: {e}", interaction.id);
+            return;
+        }
+
+        self.add_pending_interaction(
+            interaction.id,
+            interaction.channel_id,
+            interaction.token.clone(),
+        )
+        .await;
+
+        let values = match interaction.data.kind {
+            ComponentInteractionDataKind::StringSelect { values } => values,
+            _ => vec![],
+        };
+        let info = interaction_info(
+            interaction.channel_id,
+            interaction.guild_id,
+            &interaction.user,
+            interaction.member.as_ref(),
+        );
         let request = Request {
-            user: user.get(),
-            message: Some(Command(command)),
+            user: interaction.user.id.get(),
+            info: Some(info),
+            message: Some(InteractionMessage(Interaction {
+                interaction_id: interaction.id.get(),
+                custom_id: interaction.data.custom_id,
+                message_id: interaction.message.id.get(),
+                values,
+            })),
         };
-        let data = request.encode_to_vec();
-
-        self._send_data(channel, data).await
+        if let Err(e) = self._send_data(interaction.channel_id, request).await {
+            error!("Failed to forward interaction: {e}");
+        }
     }
 
-    async fn _send_data(&self, channel: ChannelId, data: Vec<u8>) -> eyre::Result<()> {
-        let length = u32::try_from(data.len())?;
-        let length_buf = &mut [0u8; 4];
-        LittleEndian::write_u32(length_buf, length);
+    /// Acknowledge a slash command and forward it to the clients bound to its channel.
+    pub async fn send_slash_command(&self, discord: &dyn Discord, command: CommandInteraction) {
+        let Some(definition) = self
+            .find_command(command.channel_id, &command.data.name)
+            .await
+        else {
+            let message = CreateInteractionResponseMessage::new()
+                .content("This command is not available in this channel.")
+                .ephemeral(true);
+            if let Err(e) = discord
+                .respond_to_interaction(
+                    command.id,
+                    &command.token,
+                    CreateInteractionResponse::Message(message),
+                )
+                .await
+            {
+                error!("Failed to respond to command {}: {e}", command.id);
+            }
+            return;
+        };
 
-        let c = self.clients.lock().await;
+        let defer = CreateInteractionResponseMessage::new().ephemeral(definition.ephemeral);
+        if let Err(e) = discord
+            .respond_to_interaction(
+                command.id,
+                &command.token,
+                CreateInteractionResponse::Defer(defer),
+            )
+            .await
+        {
+            error!("Failed to defer command {}: {e}", command.id);
+            return;
+        }
 
-        let mut found = 0;
-        for client in c.as_slice() {
-            if channel.get() != 0 && channel.get() == client.channel.read().await.get() {
-                let mut tcpstream = client.tcpstream.write().await;
+        self.add_pending_interaction(command.id, command.channel_id, command.token.clone())
+            .await;
 
-                if tcpstream.write_all(length_buf).await.is_err() {
-                    error!("Failed to send length");
-                    continue;
-                }
-                if tcpstream.write_all(&data).await.is_err() {
-                    error!("Failed to send message");
-                    continue;
+        let info = interaction_info(
+            command.channel_id,
+            command.guild_id,
+            &command.user,
+            command.member.as_ref().map(|member| member.as_ref()),
+        );
+        let request = Request {
+            user: command.user.id.get(),
+            info: Some(info),
+            message: Some(SlashCommandMessage(SlashCommand {
+                interaction_id: command.id.get(),
+                arguments: parse_arguments(&command.data.options),
+                name: command.data.name,
+            })),
+        };
+        if let Err(e) = self._send_data(command.channel_id, request).await {
+            error!("Failed to forward command: {e}");
+        }
+    }
+
+    async fn find_command(&self, channel: ChannelId, name: &str) -> Option<CommandDefinition> {
+        for client in self.clients_in(channel).await {
+            let commands = client.commands.lock().await;
+            if let Some(definition) = commands.iter().find(|c| c.name == name) {
+                return Some(definition.clone());
+            }
+        }
+        None
+    }
+
+    /// Slash commands are registered per guild, so only one definition of a name can be
+    /// registered for all the clients in it.
+    async fn check_commands(
+        &self,
+        client: &Arc<DiscordSettings>,
+        guild: GuildId,
+        commands: &[CommandDefinition],
+    ) -> eyre::Result<()> {
+        for other in self.clients.lock().await.iter() {
+            if Arc::ptr_eq(other, client) || Some(guild) != *other.guild.lock().await {
+                continue;
+            }
+            for registered in other.commands.lock().await.iter() {
+                if commands
+                    .iter()
+                    .any(|c| c.name == registered.name && c != registered)
+                {
+                    return Err(ShimError::new(
+                        ErrorCode::DuplicateCommand,
+                        format!(
+                            "Command /{} is already registered differently by another client in \
+                             guild {guild}",
+                            registered.name
+                        ),
+                    )
+                    .into());
                 }
-                found += 1;
             }
         }
-        info!("Sent message to {found} clients");
         Ok(())
     }
 
+    // Guild commands are replaced as a whole, so merge the commands of every client in the guild.
+    async fn sync_commands(&self, discord: &dyn Discord, guild: GuildId) -> eyre::Result<()> {
+        let mut names = HashSet::new();
+        let mut commands = vec![];
+        for client in self.clients.lock().await.iter() {
+            if Some(guild) != *client.guild.lock().await {
+                continue;
+            }
+            for definition in client.commands.lock().await.iter() {
+                if names.insert(definition.name.clone()) {
+                    commands.push(create_command(definition));
+                }
+            }
+        }
+        commands.truncate(DISCORD_MAX_COMMANDS);
+
+        discord.set_guild_commands(guild, commands).await
+    }
+
+    async fn add_pending_interaction(
+        &self,
+        interaction_id: InteractionId,
+        channel: ChannelId,
+        token: String,
+    ) {
+        let now = SystemTime::now();
+        let mut interactions = self.interactions.lock().await;
+        interactions.retain(|_, pending| !pending.expired(now));
+        interactions.insert(
+            interaction_id.get(),
+            PendingInteraction {
+                channel,
+                token,
+                received: now,
+            },
+        );
+    }
+
+    async fn interaction_token(
+        &self,
+        interaction_id: u64,
+        channel: ChannelId,
+    ) -> eyre::Result<String> {
+        let interactions = self.interactions.lock().await;
+        match interactions.get(&interaction_id) {
+            Some(pending) if pending.channel == channel && !pending.expired(SystemTime::now()) => {
+                Ok(pending.token.clone())
+            }
+            _ => Err(ShimError::new(
+                ErrorCode::UnknownInteraction,
+                format!("Interaction {interaction_id} is unknown or has expired"),
+            )
+            .into()),
+        }
+    }
+
+    /// Forward an attachment to the clients bound to `channel` that its message's `content` is
+    /// addressed to, by their prefix or a mention of the bot, like `send_command`.
     pub async fn send_file(
         &self,
         channel: ChannelId,
         user: UserId,
-        filename: String,
-        file: Vec<u8>,
+        bot: UserId,
+        content: &str,
+        file: ProtoFile,
+        info: MessageInfo,
     ) -> eyre::Result<()> {
-        let req_file = ProtoFile {
-            data: file,
-            filename,
-        };
-
         let request = Request {
             user: user.get(),
-            message: Some(File(req_file)),
+            info: Some(info),
+            message: Some(File(file)),
         };
 
-        let data = request.encode_to_vec();
+        let mut found = 0;
+        for client in self.clients_in(channel).await {
+            if strip_prefix(content, &client.prefix.lock().await, bot).is_none() {
+                continue;
+            }
+            if client.send(request.clone()).is_err() {
+                continue;
+            }
+            found += 1;
+        }
+        info!("Sent file to {found} clients");
+        Ok(())
+    }
+
+    pub(crate) async fn server_stats(&self) -> ServerStats {
+        let mut stats = ServerStats {
+            channels: self.channels.read().await.channels.len() as u64,
+            ..Default::default()
+        };
+        for client in self.clients.lock().await.iter() {
+            stats.clients += 1;
+            stats.num_messages += *client.num_messages.lock().await;
+            stats.total_data += *client.total_data.lock().await as u64;
+            stats.dropped_messages += client.outbound.dropped();
+        }
+        stats
+    }
 
-        self._send_data(channel, data).await
+    pub(crate) async fn channel_status(
+        &self,
+        discord: &dyn Discord,
+        channel: ChannelId,
+    ) -> ChannelStatus {
+        let guild_id = match discord.channel_guild(channel).await {
+            Ok(guild) => Some(guild.map_or(0, |guild| guild.get())),
+            Err(_) => None,
+        };
+        ChannelStatus {
+            accessible: guild_id.is_some(),
+            guild_id: guild_id.unwrap_or_default(),
+            clients: self.clients_in(channel).await.len() as u32,
+            pairing_required: !self.trusted_channels.contains(&channel),
+        }
     }
 
-    pub async fn send_stats(&self, channel: ChannelId, ctx: Context) {
+    pub async fn send_stats(&self, channel: ChannelId, discord: &dyn Discord) {
         let mut wtr = Writer::from_writer(vec![]);
         let c = self.clients.lock().await;
         for client in c.as_slice() {
@@ -352,11 +1424,14 @@ impl Server {
         }
         wtr.flush().unwrap();
 
-        let files = vec![CreateAttachment::bytes(
-            Cow::from(wtr.into_inner().unwrap()),
-            String::from("stats.csv"),
-        )];
-        let result = channel.send_files(&ctx, files, CreateMessage::new()).await;
+        let message = OutgoingMessage {
+            attachments: vec![CreateAttachment::bytes(
+                Cow::from(wtr.into_inner().unwrap()),
+                String::from("stats.csv"),
+            )],
+            ..Default::default()
+        };
+        let result = discord.send_message(channel, message).await;
         if result.is_err() {
             let error = result.err().unwrap();
             error!("{error}");
@@ -364,45 +1439,715 @@ impl Server {
     }
 }
 
-fn extract_mentions(e: &EmbedContent) -> String {
-    let mut mentions = String::new();
-    let re = Regex::new(r"(<@[0-9a-zA-Z]*>)").unwrap();
-    for (_, [mention]) in re.captures_iter(e.title.as_str()).map(|c| c.extract()) {
-        mentions = mentions + mention + " ";
+fn create_embed(e: EmbedContent) -> (CreateEmbed, Vec<CreateAttachment>) {
+    let mut author = CreateEmbedAuthor::new(e.author);
+    if !e.author_icon_url.is_empty() {
+        author = author.icon_url(e.author_icon_url);
+    }
+    if !e.author_url.is_empty() {
+        author = author.url(e.author_url);
     }
-    for (_, [mention]) in re
-        .captures_iter(e.description.as_str())
-        .map(|c| c.extract())
+    let mut embed = CreateEmbed::new()
+        .title(e.title)
+        .description(e.description)
+        .color(e.color)
+        .author(author);
+    for field in e.textfield {
+        embed = embed.field(field.title, field.text, field.inline);
+    }
+    if !e.url.is_empty() {
+        embed = embed.url(e.url);
+    }
+    if !e.footer.is_empty() {
+        let mut footer = CreateEmbedFooter::new(e.footer);
+        if !e.footer_icon_url.is_empty() {
+            footer = footer.icon_url(e.footer_icon_url);
+        }
+        embed = embed.footer(footer);
+    }
+    if e.timestamp != 0
+        && let Ok(timestamp) = Timestamp::from_unix_timestamp(e.timestamp)
     {
-        mentions = mentions + mention + " ";
+        embed = embed.timestamp(timestamp);
+    }
+
+    let mut attachments = vec![];
+    match e.snapshot {
+        Some(snapshot) => {
+            embed = embed.image(format!("attachment://{}", snapshot.filename));
+            attachments.push(CreateAttachment::bytes(snapshot.data, snapshot.filename));
+        }
+        None if !e.image_url.is_empty() => embed = embed.image(e.image_url),
+        None => {}
+    }
+    match e.thumbnail {
+        Some(thumbnail) => {
+            embed = embed.thumbnail(format!("attachment://{}", thumbnail.filename));
+            attachments.push(CreateAttachment::bytes(thumbnail.data, thumbnail.filename));
+        }
+        None if !e.thumbnail_url.is_empty() => embed = embed.thumbnail(e.thumbnail_url),
+        None => {}
+    }
+    (embed, attachments)
+}
+
+// Only the first of the messages a Response is split into is posted as the reply.
+fn reply_reference(part: usize, reply_to: u64) -> Option<MessageId> {
+    (part == 0 && reply_to != 0).then(|| MessageId::new(reply_to))
+}
+
+fn create_components(rows: Vec<ActionRow>) -> eyre::Result<Vec<CreateActionRow>> {
+    rows.into_iter()
+        .take(DISCORD_MAX_ACTION_ROWS)
+        .map(|row| {
+            Ok(match row.select_menu {
+                Some(menu) => CreateActionRow::SelectMenu(create_select_menu(menu)?),
+                None => CreateActionRow::Buttons(
+                    row.buttons
+                        .into_iter()
+                        .take(DISCORD_MAX_BUTTONS)
+                        .map(create_button)
+                        .collect::<eyre::Result<_>>()?,
+                ),
+            })
+        })
+        .collect()
+}
+
+// Discord rejects the whole message if a custom_id is empty or too long.
+fn check_custom_id(custom_id: &str) -> eyre::Result<()> {
+    if custom_id.is_empty() || custom_id.chars().count() > DISCORD_MAX_CUSTOM_ID {
+        return Err(ShimError::new(
+            ErrorCode::MalformedMessage,
+            format!("custom_id must be 1 to {DISCORD_MAX_CUSTOM_ID} characters: {custom_id:?}"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn create_button(button: Button) -> eyre::Result<CreateButton> {
+    check_custom_id(&button.custom_id)?;
+    let style = match button.style() {
+        messages::ButtonStyle::Primary => ButtonStyle::Primary,
+        messages::ButtonStyle::Secondary => ButtonStyle::Secondary,
+        messages::ButtonStyle::Success => ButtonStyle::Success,
+        messages::ButtonStyle::Danger => ButtonStyle::Danger,
+    };
+    let mut created = CreateButton::new(button.custom_id)
+        .label(button.label)
+        .style(style)
+        .disabled(button.disabled);
+    if !button.emoji.is_empty() {
+        created = created.emoji(ReactionType::Unicode(button.emoji));
+    }
+    Ok(created)
+}
+
+fn create_select_menu(menu: SelectMenu) -> eyre::Result<CreateSelectMenu> {
+    check_custom_id(&menu.custom_id)?;
+    let options = menu
+        .options
+        .into_iter()
+        .take(DISCORD_MAX_SELECT_OPTIONS)
+        .map(|option| {
+            let mut created = CreateSelectMenuOption::new(option.label, option.value)
+                .default_selection(option.selected);
+            if !option.description.is_empty() {
+                created = created.description(option.description);
+            }
+            created
+        })
+        .collect();
+    let mut created =
+        CreateSelectMenu::new(menu.custom_id, CreateSelectMenuKind::String { options })
+            .disabled(menu.disabled);
+    if !menu.placeholder.is_empty() {
+        created = created.placeholder(menu.placeholder);
+    }
+    if menu.min_values > 0 {
+        created = created.min_values(menu.min_values.min(DISCORD_MAX_SELECT_OPTIONS as u32) as u8);
+    }
+    if menu.max_values > 0 {
+        created = created.max_values(menu.max_values.min(DISCORD_MAX_SELECT_OPTIONS as u32) as u8);
+    }
+    Ok(created)
+}
+
+fn to_message_id(message_id: u64) -> eyre::Result<MessageId> {
+    if message_id == 0 {
+        return Err(ShimError::new(ErrorCode::MalformedMessage, "message_id is required").into());
+    }
+    Ok(MessageId::new(message_id))
+}
+
+fn not_paired() -> eyre::Report {
+    ShimError::new(
+        ErrorCode::NotPaired,
+        "Client is not paired with a channel, send Settings with a pairing code first",
+    )
+    .into()
+}
+
+impl From<Error> for Request {
+    fn from(error: Error) -> Self {
+        Request {
+            user: 0,
+            info: None,
+            message: Some(ErrorMessage(error)),
+        }
+    }
+}
+
+impl From<Ack> for Request {
+    fn from(ack: Ack) -> Self {
+        Request {
+            user: 0,
+            info: None,
+            message: Some(AckMessage(ack)),
+        }
+    }
+}
+
+impl From<ServerHello> for Request {
+    fn from(hello: ServerHello) -> Self {
+        Request {
+            user: 0,
+            info: None,
+            message: Some(HelloMessage(hello)),
+        }
+    }
+}
+
+impl From<SessionInfo> for Request {
+    fn from(session: SessionInfo) -> Self {
+        Request {
+            user: 0,
+            info: None,
+            message: Some(Session(session)),
+        }
+    }
+}
+
+/// Describe a newly posted message.
+pub fn message_info(message: &DiscordMessage) -> MessageInfo {
+    MessageInfo {
+        message_id: message.id.get(),
+        channel_id: message.channel_id.get(),
+        guild_id: message.guild_id.map_or(0, |guild| guild.get()),
+        username: message.author.name.clone(),
+        display_name: display_name(&message.author, message.member.as_ref().map(|m| &m.nick)),
+        role_ids: message
+            .member
+            .as_ref()
+            .map_or(vec![], |m| role_ids(&m.roles)),
+        timestamp: message.timestamp.unix_timestamp(),
+        edited: false,
     }
-    mentions
+}
+
+/// Describe an edited message. Returns None when the author is unknown.
+pub fn edited_message_info(event: &MessageUpdateEvent) -> Option<MessageInfo> {
+    let author = event.author.as_ref()?;
+    let member = event.member.as_ref().and_then(|member| member.as_ref());
+    let timestamp = event.edited_timestamp.or(event.timestamp);
+    Some(MessageInfo {
+        message_id: event.id.get(),
+        channel_id: event.channel_id.get(),
+        guild_id: event.guild_id.map_or(0, |guild| guild.get()),
+        username: author.name.clone(),
+        display_name: display_name(author, member.map(|m| &m.nick)),
+        role_ids: member.map_or(vec![], |m| role_ids(&m.roles)),
+        timestamp: timestamp.map_or(0, |t| t.unix_timestamp()),
+        edited: true,
+    })
+}
+
+fn interaction_info(
+    channel: ChannelId,
+    guild: Option<GuildId>,
+    user: &User,
+    member: Option<&Member>,
+) -> MessageInfo {
+    MessageInfo {
+        message_id: 0,
+        channel_id: channel.get(),
+        guild_id: guild.map_or(0, |guild| guild.get()),
+        username: user.name.clone(),
+        display_name: display_name(user, member.map(|m| &m.nick)),
+        role_ids: member.map_or(vec![], |m| role_ids(&m.roles)),
+        timestamp: Timestamp::now().unix_timestamp(),
+        edited: false,
+    }
+}
+
+fn display_name(user: &User, nick: Option<&Option<String>>) -> String {
+    match nick {
+        Some(Some(nick)) => nick.clone(),
+        _ => user.display_name().to_string(),
+    }
+}
+
+fn role_ids(roles: &[RoleId]) -> Vec<u64> {
+    roles.iter().map(|role| role.get()).collect()
+}
+
+fn strip_prefix<'a>(command: &'a str, prefix: &str, bot: UserId) -> Option<&'a str> {
+    for mention in [format!("<@{bot}>"), format!("<@!{bot}>")] {
+        if let Some(stripped) = command.strip_prefix(&mention) {
+            return Some(stripped.trim_start());
+        }
+    }
+    // Clients that don't set a prefix receive every message.
+    if prefix.is_empty() {
+        return Some(command);
+    }
+    command.strip_prefix(prefix)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{messages::EmbedContent, server::extract_mentions};
+    use std::{net::Shutdown, sync::Arc, time::Duration};
+
+    use async_std::{
+        channel::Receiver,
+        future::timeout,
+        net::{TcpListener, TcpStream},
+        task,
+    };
+    use serenity::{
+        all::{CreateActionRow, User},
+        model::id::{ChannelId, GuildId, UserId},
+    };
+
+    use crate::{
+        config::ServerConfig,
+        error::to_client_error,
+        fake::FakeDiscord,
+        messages::{
+            ActionRow,
+            Button,
+            CommandDefinition,
+            ErrorCode,
+            RegisterCommands,
+            Request,
+            Response,
+            SelectMenu,
+            SelectOption,
+            request::Message::Command,
+            response::Field,
+        },
+        outbound::{OutboundQueue, SlowConsumerPolicy},
+        server::{
+            ChannelIndex,
+            DiscordSettings,
+            PendingRequests,
+            Server,
+            create_components,
+            display_name,
+            strip_prefix,
+            to_message_id,
+        },
+        transport::{Socket, Transport},
+    };
 
     #[test]
-    fn test_extract_mentions_empty() {
-        let e = EmbedContent::default();
-        let mentions = extract_mentions(&e);
-        assert_eq!("", mentions);
+    fn test_channel_index() {
+        let channel = ChannelId::new(1);
+        let other = ChannelId::new(2);
+        let first = Arc::new(1);
+        let second = Arc::new(2);
+
+        let mut index = ChannelIndex::new();
+        index.insert(channel, &first);
+        index.insert(channel, &first);
+        index.insert(channel, &second);
+        assert_eq!(vec![first.clone(), second.clone()], index.get(channel));
+        assert!(index.get(other).is_empty());
+
+        index.remove(channel, &first);
+        assert_eq!(vec![second.clone()], index.get(channel));
+        index.remove(channel, &second);
+        assert!(index.channels.is_empty());
     }
 
     #[test]
-    fn test_extract_mentions_title() {
-        let mut e = EmbedContent::default();
-        e.title = "<@12345678910> <@Everyone>".to_string();
-        let mentions = extract_mentions(&e);
-        assert_eq!("<@12345678910> <@Everyone> ", mentions);
+    fn test_strip_prefix() {
+        let bot = UserId::new(1234);
+        assert_eq!(Some("status"), strip_prefix("/status", "/", bot));
+        assert_eq!(None, strip_prefix("hello everyone", "/", bot));
+        assert_eq!(
+            Some("hello everyone"),
+            strip_prefix("hello everyone", "", bot)
+        );
     }
 
     #[test]
-    fn test_extract_mentions_description() {
-        let mut e = EmbedContent::default();
-        e.description = "<@12345678910> <@Everyone>".to_string();
-        let mentions = extract_mentions(&e);
-        assert_eq!("<@12345678910> <@Everyone> ", mentions);
+    fn test_strip_prefix_mention() {
+        let bot = UserId::new(1234);
+        assert_eq!(Some("status"), strip_prefix("<@1234> status", "/", bot));
+        assert_eq!(Some("status"), strip_prefix("<@!1234> status", "/", bot));
+        assert_eq!(None, strip_prefix("<@5678> status", "/", bot));
+    }
+
+    #[test]
+    fn test_display_name() {
+        let mut user = User::default();
+        user.name = "username".to_string();
+        assert_eq!("username", display_name(&user, None));
+        assert_eq!("username", display_name(&user, Some(&None)));
+        assert_eq!(
+            "nickname",
+            display_name(&user, Some(&Some("nickname".to_string())))
+        );
+
+        user.global_name = Some("global".to_string());
+        assert_eq!("global", display_name(&user, None));
+    }
+
+    #[test]
+    fn test_to_message_id() {
+        assert!(to_message_id(0).is_err());
+        assert_eq!(1234, to_message_id(1234).unwrap().get());
+    }
+
+    #[test]
+    fn test_create_components_limits() {
+        let buttons = (0..10)
+            .map(|i| Button {
+                custom_id: i.to_string(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let rows = (0..10)
+            .map(|_| ActionRow {
+                buttons: buttons.clone(),
+                select_menu: None,
+            })
+            .collect();
+
+        let components = create_components(rows).unwrap();
+        assert_eq!(5, components.len());
+        for row in components {
+            match row {
+                CreateActionRow::Buttons(buttons) => assert_eq!(5, buttons.len()),
+                _ => panic!("Expected a row of buttons"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_create_components_select_menu_replaces_buttons() {
+        let rows = vec![ActionRow {
+            buttons: vec![Button::default()],
+            select_menu: Some(SelectMenu {
+                custom_id: "file".to_string(),
+                options: vec![SelectOption {
+                    label: "benchy.gcode".to_string(),
+                    value: "benchy.gcode".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+        }];
+
+        let components = create_components(rows).unwrap();
+        assert!(matches!(components[0], CreateActionRow::SelectMenu(_)));
+    }
+
+    #[async_std::test]
+    async fn test_interaction_token_unknown() {
+        let server = Server::default();
+        assert!(
+            server
+                .interaction_token(1234, ChannelId::new(1234))
+                .await
+                .is_err()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_pairing_code_single_use() {
+        let server = Server::default();
+        let channel = ChannelId::new(1234);
+        let code = server.create_pairing_code(channel).await;
+
+        assert!(server.consume_pairing_code(&code, channel).await);
+        assert!(!server.consume_pairing_code(&code, channel).await);
+    }
+
+    #[async_std::test]
+    async fn test_pairing_code_wrong_channel() {
+        let server = Server::default();
+        let code = server.create_pairing_code(ChannelId::new(1234)).await;
+
+        assert!(
+            !server
+                .consume_pairing_code(&code, ChannelId::new(5678))
+                .await
+        );
+        assert!(
+            !server
+                .consume_pairing_code(&code, ChannelId::new(1234))
+                .await
+        );
+    }
+
+    #[async_std::test]
+    async fn test_pairing_code_empty() {
+        let server = Server::default();
+        server.create_pairing_code(ChannelId::new(1234)).await;
+
+        assert!(!server.consume_pairing_code("", ChannelId::new(1234)).await);
+    }
+
+    fn client(peer_addr: &str) -> (Arc<DiscordSettings>, Receiver<Request>) {
+        let (outbound, receiver) = OutboundQueue::new(8, SlowConsumerPolicy::DropOldest, None);
+        let settings = DiscordSettings::new(peer_addr.to_string(), outbound);
+        (Arc::new(settings), receiver)
+    }
+
+    fn request(user: u64) -> Request {
+        Request {
+            user,
+            ..Default::default()
+        }
+    }
+
+    // A client bound to `channel` that has disconnected, with its session detached.
+    async fn detached_client(server: &Server, channel: ChannelId) -> Arc<DiscordSettings> {
+        let (old, _) = client("old");
+        *old.session_token.lock().await = Some("token".to_string());
+        *old.prefix.lock().await = "/".to_string();
+        server.bind_channel(&old, channel).await;
+        server.clients.lock().await.push(old.clone());
+        old.outbound.close();
+        assert_eq!(Some("token".to_string()), server.detach_session(&old).await);
+        old
+    }
+
+    #[async_std::test]
+    async fn test_resume_session() {
+        let server = Server::default();
+        let channel = ChannelId::new(1234);
+        detached_client(&server, channel).await;
+        server._send_data(channel, request(1)).await.unwrap();
+        server._send_data(channel, request(2)).await.unwrap();
+
+        let (new, _) = client("new");
+        assert!(server.resume_session(&new, "unknown").await.is_none());
+        let pending = server.resume_session(&new, "token").await.unwrap();
+        assert_eq!(vec![request(1), request(2)], pending);
+        assert_eq!("/", *new.prefix.lock().await);
+        assert_eq!(Some(channel), *new.channel.read().await);
+        assert_eq!(1, server.clients_in(channel).await.len());
+        assert!(Arc::ptr_eq(&new, &server.clients_in(channel).await[0]));
+        // The new client is registered by its own connection, only the old one is dropped.
+        assert!(server.clients.lock().await.is_empty());
+
+        // Sessions can only be resumed once.
+        let (other, _) = client("other");
+        assert!(server.resume_session(&other, "token").await.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_resume_session_twice() {
+        let server = Server::default();
+        let channel = ChannelId::new(1234);
+        let old = detached_client(&server, channel).await;
+
+        // The session is resumed and detached again within the first client's grace period.
+        let (new, _) = client("new");
+        server.clients.lock().await.push(new.clone());
+        server.resume_session(&new, "token").await.unwrap();
+        new.outbound.close();
+        assert_eq!(Some("token".to_string()), server.detach_session(&new).await);
+
+        // Only the client that detached the session last can expire it.
+        assert!(!server.expire_session(&old, "token").await);
+        assert!(server.expire_session(&new, "token").await);
+        assert!(server.sessions.lock().await.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_detach_session_disabled() {
+        let server = Server::new(ServerConfig {
+            session_grace_period: Duration::ZERO,
+            ..Default::default()
+        });
+        let (settings, _) = client("client");
+        *settings.session_token.lock().await = Some("token".to_string());
+        assert!(server.detach_session(&settings).await.is_none());
+
+        // Clients that never bound a channel have no session to keep.
+        let server = Server::default();
+        let (settings, _) = client("client");
+        assert!(server.detach_session(&settings).await.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_check_commands_duplicate() {
+        let server = Server::default();
+        let guild = GuildId::new(99);
+        let status = |description: &str| CommandDefinition {
+            name: "status".to_string(),
+            description: description.to_string(),
+            ..Default::default()
+        };
+        let (first, _) = client("first");
+        *first.guild.lock().await = Some(guild);
+        *first.commands.lock().await = vec![status("Print status")];
+        server.clients.lock().await.push(first.clone());
+
+        // Clients can re-register their own commands, and share identical ones.
+        let (second, _) = client("second");
+        assert!(
+            server
+                .check_commands(&first, guild, &[status("Status")])
+                .await
+                .is_ok()
+        );
+        assert!(
+            server
+                .check_commands(&second, guild, &[status("Print status")])
+                .await
+                .is_ok()
+        );
+        let error = server
+            .check_commands(&second, guild, &[status("Status")])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            ErrorCode::DuplicateCommand as i32,
+            to_client_error(0, &error).code
+        );
+        assert!(
+            server
+                .check_commands(&second, GuildId::new(100), &[status("Status")])
+                .await
+                .is_ok()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_register_commands_rejected() {
+        let server = Server::default();
+        let channel = ChannelId::new(1234);
+        let discord = Arc::new(FakeDiscord::default());
+        discord.add_channel(channel, Some(GuildId::new(99)));
+        let (settings, _) = client("first");
+        server.bind_channel(&settings, channel).await;
+        server.clients.lock().await.push(settings.clone());
+        let register = |name: &str| Response {
+            field: Some(Field::RegisterCommands(RegisterCommands {
+                commands: vec![CommandDefinition {
+                    name: name.to_string(),
+                    description: "Print status".to_string(),
+                    ..Default::default()
+                }],
+            })),
+            ..Default::default()
+        };
+
+        let handled = server.handle_task(settings.clone(), register("status"), discord.clone());
+        handled.await.unwrap();
+        let error = server
+            .handle_task(settings.clone(), register("Status"), discord.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            ErrorCode::MalformedMessage as i32,
+            to_client_error(0, &error).code
+        );
+
+        // Commands discord rejected aren't kept, or they would break every later sync.
+        discord.reject_commands(true);
+        let handled = server.handle_task(settings.clone(), register("pause"), discord.clone());
+        assert!(handled.await.is_err());
+        assert_eq!("status", settings.commands.lock().await[0].name);
+    }
+
+    // Serve a TCP client that never reads, returning its end of the connection and the task
+    // serving it, once the client is registered.
+    async fn serve_unread_client(
+        server: &Arc<Server>,
+    ) -> (TcpStream, task::JoinHandle<()>, Arc<DiscordSettings>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, writer) = Transport::LengthPrefixed
+            .open(Box::new(stream.clone()), &server.config.limits)
+            .await
+            .unwrap();
+
+        let serving = server.clone();
+        let serve = task::spawn(async move {
+            let discord = Arc::new(FakeDiscord::default());
+            let socket = Some(Socket::Tcp(stream));
+            serving
+                .serve_client(socket, "peer".to_string(), reader, writer, discord)
+                .await;
+        });
+        while server.clients.lock().await.is_empty() {
+            task::yield_now().await;
+        }
+        let settings = server.clients.lock().await[0].clone();
+        (peer, serve, settings)
+    }
+
+    // Large enough that a few fill the socket buffers.
+    fn large_request() -> Request {
+        Request {
+            message: Some(Command(str::repeat("a", 1_000_000))),
+            ..Default::default()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_slow_consumer_disconnect() {
+        let server = Arc::new(Server::new(ServerConfig {
+            queue_capacity: 2,
+            slow_consumer: SlowConsumerPolicy::Disconnect,
+            ..Default::default()
+        }));
+        let (_peer, serve, settings) = serve_unread_client(&server).await;
+
+        let mut sent = 0;
+        while settings.send(large_request()).is_ok() {
+            sent += 1;
+            assert!(sent < 1000, "Client was never disconnected");
+            task::yield_now().await;
+        }
+        // The writer is blocked on the client, so only shutting the socket down gets it out.
+        timeout(Duration::from_secs(5), serve).await.unwrap();
+        assert!(server.clients.lock().await.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_drain_timeout() {
+        let mut config = ServerConfig::default();
+        config.limits.frame_timeout = Duration::from_millis(100);
+        let server = Arc::new(Server::new(config));
+        let (peer, serve, settings) = serve_unread_client(&server).await;
+
+        for _ in 0..50 {
+            settings.send(large_request()).unwrap();
+            task::sleep(Duration::from_millis(1)).await;
+        }
+        // The client hangs up its side without reading what was sent to it.
+        peer.shutdown(Shutdown::Write).unwrap();
+        timeout(Duration::from_secs(5), serve).await.unwrap();
+        assert!(server.clients.lock().await.is_empty());
+    }
+
+    #[test]
+    fn test_pending_requests_drop_oldest() {
+        let mut pending = PendingRequests::new(2);
+        for user in 1..=3 {
+            pending.push(request(user));
+        }
+        assert_eq!(vec![request(2), request(3)], Vec::from(pending.requests));
     }
 }
@@ -1,188 +1,31 @@
 #[cfg(test)]
 mod tests {
-    use std::{
-        fs::File,
-        io::{Read, Write},
-        net::{Shutdown, TcpStream},
-    };
-
-    use byteorder::{ByteOrder, LittleEndian};
-    use prost::Message;
+    use std::{fs::File, io::Read};
 
     use crate::{
         embedbuilder::{
+            AttachmentLimits,
             DISCORD_MAX_AUTHOR,
             DISCORD_MAX_DESCRIPTION,
+            DISCORD_MAX_EMBED_TOTAL,
             DISCORD_MAX_FIELDS,
+            DISCORD_MAX_FOOTER,
             DISCORD_MAX_TITLE,
             DISCORD_MAX_VALUE,
             ONE_MEGABYTE,
             build_embeds,
             split_file,
         },
-        messages,
-        messages::{EmbedContent, Response, Settings, TextField},
+        messages::{ActionRow, Button, EmbedContent, TextField},
     };
 
-    static CHANNEL_ID: u64 = 467700763775205396;
-
-    fn send_message(stream: &mut TcpStream, response: &mut Response) {
-        let msg = response.encode_to_vec();
-        let length = msg.len() as u32;
-        let length_buf = &mut [0u8; 4];
-        LittleEndian::write_u32(length_buf, length);
-
-        stream.write_all(length_buf).unwrap();
-        stream.write_all(msg.as_slice()).unwrap();
-    }
-
-    fn recv_message(stream: &mut TcpStream) -> messages::Request {
-        let length_buf = &mut [0u8; 4];
-        stream.read_exact(length_buf).unwrap();
-        let length = LittleEndian::read_u32(length_buf);
-
-        let mut buf = vec![0u8; length as usize];
-        stream.read_exact(&mut buf).unwrap();
-
-        return messages::Request::decode(buf.as_slice()).unwrap();
-    }
-
-    fn get_snapshot() -> messages::ProtoFile {
-        let mut file = messages::ProtoFile {
-            filename: "filename.png".to_string(),
-            ..Default::default()
-        };
-
-        let filedata = match std::fs::read("test_data/test_pattern.png") {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::PermissionDenied {
-                    eprintln!("please run again with appropriate permissions.");
-                }
-                panic!("{}", e);
-            }
-        };
-        file.data = filedata;
-        file
-    }
-
-    #[ignore]
-    #[test]
-    fn test_send_file() {
-        let mut stream = TcpStream::connect("localhost:12345").unwrap();
-        println!("Successfully connected to server in port 12345");
-
-        let settings = Settings {
-            channel_id: CHANNEL_ID,
-            ..Default::default()
-        };
-        let mut response = Response {
-            field: Some(messages::response::Field::Settings(settings)),
-            ..Default::default()
-        };
-
-        send_message(&mut stream, &mut response);
-
-        let mut response = Response::default();
-        let snapshot = get_snapshot();
-        response.field = Some(messages::response::Field::File(snapshot));
-
-        send_message(&mut stream, &mut response);
-
-        stream.shutdown(Shutdown::Both).unwrap();
-        println!("Terminated.");
-    }
-
-    #[ignore]
-    #[test]
-    fn test_send_embed() {
-        let mut stream = TcpStream::connect("localhost:12345").unwrap();
-        println!("Successfully connected to server in port 12345");
-
-        let settings = Settings {
-            channel_id: CHANNEL_ID,
-            ..Default::default()
-        };
-        let mut response = Response {
-            field: Some(messages::response::Field::Settings(settings)),
-            ..Default::default()
-        };
-
-        send_message(&mut stream, &mut response);
-
-        let mut response = Response::default();
-        let mut discord_embed = messages::EmbedContent {
-            title: "Title".to_string(),
-            description: "Description".to_string(),
-            author: "Author".to_string(),
-            color: 0x123456,
-            ..Default::default()
-        };
-        let snapshot = get_snapshot();
-        discord_embed.snapshot = Some(snapshot);
-        for i in 0..50 {
-            let field = TextField {
-                title: i.to_string(),
-                text: "".to_string(),
-                inline: true,
-                ..Default::default()
-            };
-            discord_embed.textfield.insert(0, field);
-        }
-        response.field = Some(messages::response::Field::Embed(discord_embed));
-
-        send_message(&mut stream, &mut response);
-
-        stream.shutdown(Shutdown::Both).unwrap();
-        println!("Terminated.");
-    }
-
-    #[ignore]
-    #[test]
-    fn test_recv_message() {
-        let mut stream = TcpStream::connect("localhost:12345").unwrap();
-        println!("Successfully connected to server in port 12345");
-
-        let settings = Settings {
-            channel_id: CHANNEL_ID,
-            ..Default::default()
-        };
-        let mut response = Response {
-            field: Some(messages::response::Field::Settings(settings)),
-            ..Default::default()
-        };
-
-        send_message(&mut stream, &mut response);
-        let mut seen_file = false;
-        let mut seen_command = false;
-        loop {
-            let request = recv_message(&mut stream);
-            match request.message {
-                None => {}
-                Some(messages::request::Message::File(file)) => {
-                    println!(
-                        "Received file: [{}], size: [{}]",
-                        file.filename,
-                        file.data.len()
-                    );
-                    assert_ne!(request.user, 0);
-                    seen_file = true;
-                }
-                Some(messages::request::Message::Command(command)) => {
-                    println!("Received command: [{}]", command);
-                    assert_ne!(request.user, 0);
-                    seen_command = true;
-                }
-            }
-            if seen_file && seen_command {
-                break;
-            }
-        }
-    }
-
     #[test]
     fn test_split_file_small_file() {
-        let attachments = split_file("filename".to_string(), "filedata".as_bytes());
+        let attachments = split_file(
+            "filename".to_string(),
+            "filedata".as_bytes(),
+            &AttachmentLimits::default(),
+        );
         assert_eq!(attachments.len(), 1);
         assert_eq!(attachments[0].0, "filename");
     }
@@ -192,7 +35,11 @@ mod tests {
         let mut file = File::open("/dev/urandom").unwrap();
         let mut filedata = vec![0u8; 7 * ONE_MEGABYTE];
         file.read_exact(&mut filedata).unwrap();
-        let attachments = split_file("filename".to_string(), &filedata);
+        let attachments = split_file(
+            "filename".to_string(),
+            &filedata,
+            &AttachmentLimits::default(),
+        );
         assert_eq!(attachments.len(), 8);
         assert_eq!(attachments[0].0, "filename.zip.000");
         assert_eq!(attachments[1].0, "filename.zip.001");
@@ -218,6 +65,7 @@ mod tests {
             color: 0,
             snapshot: Default::default(),
             textfield: textfields,
+            ..Default::default()
         };
 
         let embeds = build_embeds(ec);
@@ -241,6 +89,7 @@ mod tests {
             color: 0,
             snapshot: Default::default(),
             textfield: textfields,
+            ..Default::default()
         };
 
         let embeds = build_embeds(ec.clone());
@@ -257,4 +106,108 @@ mod tests {
 
         assert_eq!(num_fields, DISCORD_MAX_FIELDS + 1);
     }
+
+    #[test]
+    fn test_build_embeds_components_on_last() {
+        let textfields = (0..(DISCORD_MAX_FIELDS + 1))
+            .map(|i| TextField {
+                title: i.to_string(),
+                text: i.to_string(),
+                inline: false,
+            })
+            .collect();
+        let components = vec![ActionRow {
+            buttons: vec![Button {
+                custom_id: "pause".to_string(),
+                label: "Pause".to_string(),
+                ..Default::default()
+            }],
+            select_menu: None,
+        }];
+        let ec = EmbedContent {
+            textfield: textfields,
+            components: components.clone(),
+            ..Default::default()
+        };
+
+        let embeds = build_embeds(ec);
+        assert_eq!(2, embeds.len());
+        assert!(embeds[0].components.is_empty());
+        assert_eq!(components, embeds[1].components);
+    }
+
+    #[test]
+    fn test_build_embeds_footer_on_last() {
+        let textfields = (0..(DISCORD_MAX_FIELDS + 1))
+            .map(|i| TextField {
+                title: i.to_string(),
+                text: i.to_string(),
+                inline: false,
+            })
+            .collect();
+        let ec = EmbedContent {
+            title: "Title".to_string(),
+            url: "https://octoprint.org".to_string(),
+            footer: str::repeat("f", DISCORD_MAX_FOOTER + 1),
+            timestamp: 1700000000,
+            image_url: "https://octoprint.org/image.png".to_string(),
+            textfield: textfields,
+            ..Default::default()
+        };
+
+        let embeds = build_embeds(ec);
+        assert_eq!(2, embeds.len());
+        assert_eq!("https://octoprint.org", embeds[0].url);
+        assert_eq!("https://octoprint.org/image.png", embeds[0].image_url);
+        assert_eq!("", embeds[0].footer);
+        assert_eq!(0, embeds[0].timestamp);
+        assert_eq!("", embeds[1].url);
+        assert_eq!("", embeds[1].image_url);
+        assert_eq!(DISCORD_MAX_FOOTER, embeds[1].footer.len());
+        assert_eq!(1700000000, embeds[1].timestamp);
+    }
+
+    #[test]
+    fn test_build_embeds_multibyte_footer() {
+        // Each é is two bytes, so after the "a" the limit falls inside one.
+        let ec = EmbedContent {
+            footer: format!("a{}", str::repeat("é", DISCORD_MAX_FOOTER)),
+            ..Default::default()
+        };
+
+        let embeds = build_embeds(ec);
+        let footer = format!("a{}", str::repeat("é", DISCORD_MAX_FOOTER / 2 - 1));
+        assert_eq!(footer, embeds[0].footer);
+    }
+
+    #[test]
+    fn test_build_embeds_footer_counts_toward_total() {
+        let textfields = (0..5)
+            .map(|_| TextField {
+                title: str::repeat("d", DISCORD_MAX_TITLE),
+                text: str::repeat("e", DISCORD_MAX_VALUE),
+                inline: false,
+            })
+            .collect();
+        let ec = EmbedContent {
+            description: str::repeat("b", 100),
+            footer: str::repeat("f", DISCORD_MAX_FOOTER),
+            textfield: textfields,
+            ..Default::default()
+        };
+
+        let embeds = build_embeds(ec);
+        for embed in embeds {
+            let total = embed.title.len()
+                + embed.description.len()
+                + embed.author.len()
+                + embed.footer.len()
+                + embed
+                    .textfield
+                    .iter()
+                    .map(|f| f.title.len() + f.text.len())
+                    .sum::<usize>();
+            assert!(total <= DISCORD_MAX_EMBED_TOTAL);
+        }
+    }
 }
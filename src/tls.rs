@@ -0,0 +1,178 @@
+use std::{path::Path, sync::Arc};
+
+use color_eyre::{eyre, eyre::eyre};
+use futures_rustls::{
+    TlsAcceptor,
+    TlsConnector,
+    rustls::{
+        ClientConfig,
+        RootCertStore,
+        ServerConfig,
+        crypto::{CryptoProvider, ring},
+        server::WebPkiClientVerifier,
+    },
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+use crate::config::TlsConfig;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Build the acceptor for the TLS listener, requiring client certificates if `client_ca` is set.
+pub fn acceptor(config: &TlsConfig) -> eyre::Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+
+    let builder =
+        ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(client_ca)?),
+                provider(),
+            )
+            .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| eyre!("Invalid TLS certificate {}: {e}", config.cert.display()))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Build a connector for clients. The server is verified against `ca`, or the public web roots
+/// if unset. `identity` is the certificate and key to present if the server requires one.
+pub fn connector(
+    ca: Option<&Path>,
+    identity: Option<(&Path, &Path)>,
+) -> eyre::Result<TlsConnector> {
+    let roots = match ca {
+        Some(ca) => load_roots(ca)?,
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let client_config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+fn load_certs(path: &Path) -> eyre::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| eyre!("Failed to read certificates from {}: {e}", path.display()))?;
+    if certs.is_empty() {
+        return Err(eyre!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> eyre::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| eyre!("Failed to read private key from {}: {e}", path.display()))
+}
+
+fn load_roots(path: &Path) -> eyre::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use async_std::{
+        io::{ReadExt, WriteExt},
+        net::{TcpListener, TcpStream},
+        task,
+    };
+    use futures_rustls::TlsConnector;
+    use rustls_pki_types::ServerName;
+
+    use crate::{
+        config::TlsConfig,
+        tls::{acceptor, connector},
+    };
+
+    fn test_data(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test_data/tls")
+            .join(name)
+    }
+
+    fn tls_config(client_ca: bool) -> TlsConfig {
+        TlsConfig {
+            cert: test_data("server.pem"),
+            key: test_data("server.key"),
+            client_ca: client_ca.then(|| test_data("ca.pem")),
+            ..Default::default()
+        }
+    }
+
+    // Echo one byte back over TLS, returning whether it made it.
+    async fn echo(config: TlsConfig, connector: TlsConnector) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = acceptor(&config).unwrap();
+        let server = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.ok()?;
+            let buf = &mut [0u8; 1];
+            stream.read_exact(buf).await.ok()?;
+            stream.write_all(buf).await.ok()?;
+            stream.flush().await.ok()
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let client = async {
+            let mut stream = connector.connect(name, stream).await.ok()?;
+            stream.write_all(&[42]).await.ok()?;
+            let buf = &mut [0u8; 1];
+            stream.read_exact(buf).await.ok()?;
+            Some(buf[0])
+        };
+        let echoed = client.await;
+        server.await;
+        echoed == Some(42)
+    }
+
+    #[async_std::test]
+    async fn test_tls() {
+        let connector = connector(Some(&test_data("ca.pem")), None).unwrap();
+        assert!(echo(tls_config(false), connector).await);
+    }
+
+    #[async_std::test]
+    async fn test_tls_client_auth() {
+        let cert = test_data("client.pem");
+        let key = test_data("client.key");
+        let with_identity = connector(Some(&test_data("ca.pem")), Some((&cert, &key))).unwrap();
+        assert!(echo(tls_config(true), with_identity).await);
+
+        // Clients without a certificate are rejected.
+        let without_identity = connector(Some(&test_data("ca.pem")), None).unwrap();
+        assert!(!echo(tls_config(true), without_identity).await);
+    }
+
+    #[test]
+    fn test_acceptor_missing_files() {
+        let config = TlsConfig {
+            cert: test_data("missing.pem"),
+            ..tls_config(false)
+        };
+        assert!(acceptor(&config).is_err());
+    }
+}
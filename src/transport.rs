@@ -0,0 +1,376 @@
+use std::{
+    fs,
+    fs::Permissions,
+    io,
+    net::Shutdown,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use async_std::{
+    channel::{Receiver, Sender, bounded},
+    future::timeout,
+    io::{Read, Write},
+    net::TcpStream,
+    os::unix::net::{UnixListener, UnixStream},
+};
+use async_tungstenite::{
+    WebSocketReceiver,
+    WebSocketSender,
+    accept_async_with_config,
+    tungstenite::protocol::WebSocketConfig as TungsteniteConfig,
+};
+use color_eyre::{eyre, eyre::eyre};
+use futures::AsyncReadExt;
+
+use crate::{
+    inbound::{ConnectionLimits, FrameReader},
+    outbound::FrameWriter,
+};
+
+/// A client connection, a plain TCP or Unix stream, or a TLS stream.
+pub(crate) trait Connection: Read + Write + Send + Unpin {}
+
+impl<T: Read + Write + Send + Unpin> Connection for T {}
+
+pub(crate) type BoxedConnection = Box<dyn Connection>;
+pub(crate) type WebSocketReader = WebSocketReceiver<BoxedConnection>;
+pub(crate) type WebSocketWriter = WebSocketSender<BoxedConnection>;
+
+/// The socket underneath a connection, kept to disconnect the client.
+#[derive(Clone, Debug)]
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    /// Shut down both directions, so the reader and writer of the connection stop.
+    pub(crate) fn shutdown(&self) {
+        let _ = match self {
+            Socket::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Socket::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+/// Tells a WebSocket reader when bytes arrive, which tungstenite doesn't show until a whole message
+/// is in, so that a message can be timed out once it has started without timing out an idle client.
+pub(crate) struct ReadSignal {
+    armed: AtomicBool,
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl ReadSignal {
+    fn new() -> Self {
+        let (sender, receiver) = bounded(1);
+        ReadSignal {
+            armed: AtomicBool::new(false),
+            sender,
+            receiver,
+        }
+    }
+
+    /// Forget earlier reads, `arrived` completes on the next one.
+    pub(crate) fn arm(&self) {
+        while self.receiver.try_recv().is_ok() {}
+        self.armed.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) async fn arrived(&self) {
+        let _ = self.receiver.recv().await;
+    }
+
+    fn notify(&self) {
+        if self.armed.swap(false, Ordering::SeqCst) {
+            let _ = self.sender.try_send(());
+        }
+    }
+}
+
+/// A connection that notifies its `ReadSignal` whenever it reads something.
+struct SignallingConnection {
+    inner: BoxedConnection,
+    signal: Arc<ReadSignal>,
+}
+
+impl Read for SignallingConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = result
+            && read > 0
+        {
+            self.signal.notify();
+        }
+        result
+    }
+}
+
+impl Write for SignallingConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Bind a Unix socket at `path`, replacing a stale socket left by a previous run, and set its
+/// permissions to `mode` if given.
+pub(crate) async fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path).await?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// How `Response`s and `Request`s are framed on a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Transport {
+    /// Each message is prefixed with its length as a 4 byte little-endian integer.
+    LengthPrefixed,
+    /// Each message is a binary WebSocket message.
+    WebSocket,
+}
+
+impl Transport {
+    /// Split a connection into its reader and writer, completing the WebSocket handshake first.
+    pub(crate) async fn open(
+        self,
+        connection: BoxedConnection,
+        limits: &ConnectionLimits,
+    ) -> eyre::Result<(FrameReader, FrameWriter)> {
+        match self {
+            Transport::LengthPrefixed => {
+                let (reader, writer) = connection.split();
+                Ok((
+                    FrameReader::Stream(Box::new(reader)),
+                    FrameWriter::Stream(Box::new(writer)),
+                ))
+            }
+            Transport::WebSocket => {
+                let config = TungsteniteConfig::default()
+                    .max_message_size(Some(limits.max_frame_size))
+                    .max_frame_size(Some(limits.max_frame_size));
+                let signal = Arc::new(ReadSignal::new());
+                let connection: BoxedConnection = Box::new(SignallingConnection {
+                    inner: connection,
+                    signal: signal.clone(),
+                });
+                let websocket = timeout(
+                    limits.frame_timeout,
+                    accept_async_with_config(connection, Some(config)),
+                )
+                .await
+                .map_err(|_| eyre!("WebSocket handshake timed out"))??;
+                let (writer, reader) = websocket.split();
+                Ok((
+                    FrameReader::WebSocket(reader, signal),
+                    FrameWriter::WebSocket(writer),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs,
+        os::unix::fs::PermissionsExt,
+        time::{Duration, Instant},
+    };
+
+    use async_std::{
+        io::{ReadExt, WriteExt},
+        net::{TcpListener, TcpStream},
+        os::unix::net::UnixStream,
+        task,
+    };
+    use async_tungstenite::{client_async, tungstenite::Message};
+    use futures::StreamExt;
+    use prost::Message as _;
+
+    use crate::{
+        error::to_client_error,
+        inbound::ConnectionLimits,
+        messages::{ErrorCode, Request, Response},
+        transport::{Transport, bind_unix},
+    };
+
+    fn response() -> Response {
+        Response {
+            id: 5,
+            ..Default::default()
+        }
+    }
+
+    fn request() -> Request {
+        Request {
+            user: 7,
+            ..Default::default()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_websocket() {
+        let limits = ConnectionLimits {
+            max_frame_size: 8,
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = async_std::task::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (mut websocket, _) = client_async(format!("ws://{addr}/"), stream).await.unwrap();
+            websocket.send(Message::text("ignored")).await.unwrap();
+            let message = Message::binary(response().encode_to_vec());
+            websocket.send(message).await.unwrap();
+            let reply = websocket.next().await.unwrap().unwrap();
+            websocket.send(Message::binary(vec![0; 9])).await.unwrap();
+            reply
+        });
+
+        let (server, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = Transport::WebSocket
+            .open(Box::new(server), &limits)
+            .await
+            .unwrap();
+        assert_eq!(response(), reader.read(&limits).await.unwrap().unwrap());
+        writer.write(request()).await.unwrap();
+        assert_eq!(Message::binary(request().encode_to_vec()), client.await);
+
+        let error = reader.read(&limits).await.unwrap_err();
+        assert_eq!(
+            ErrorCode::FrameTooLarge as i32,
+            to_client_error(0, &error).code
+        );
+    }
+
+    #[async_std::test]
+    async fn test_websocket_frame_timeout() {
+        let limits = ConnectionLimits {
+            frame_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = async_std::task::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (websocket, _) = client_async(format!("ws://{addr}/"), stream.clone())
+                .await
+                .unwrap();
+            // Idle for longer than the frame timeout, then start a masked 5 byte binary frame
+            // and stall halfway.
+            task::sleep(Duration::from_millis(300)).await;
+            (&stream)
+                .write_all(&[0x82, 0x85, 1, 2, 3, 4, 0, 0])
+                .await
+                .unwrap();
+            task::sleep(Duration::from_secs(5)).await;
+            drop(websocket);
+        });
+
+        let (server, _) = listener.accept().await.unwrap();
+        let (mut reader, _writer) = Transport::WebSocket
+            .open(Box::new(server), &limits)
+            .await
+            .unwrap();
+        let started = Instant::now();
+        let error = reader.read(&limits).await.unwrap_err();
+        assert_eq!(ErrorCode::Timeout as i32, to_client_error(0, &error).code);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(2));
+        client.cancel().await;
+    }
+
+    #[async_std::test]
+    async fn test_length_prefixed() {
+        let limits = ConnectionLimits::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = Transport::LengthPrefixed
+            .open(Box::new(server), &limits)
+            .await
+            .unwrap();
+
+        client.write_all(&[2, 0, 0, 0]).await.unwrap();
+        client.write_all(&response().encode_to_vec()).await.unwrap();
+        assert_eq!(response(), reader.read(&limits).await.unwrap().unwrap());
+
+        // Garbage is reported, but doesn't end the connection.
+        client.write_all(&[1, 0, 0, 0, 0xff]).await.unwrap();
+        assert!(reader.read(&limits).await.unwrap().is_err());
+
+        writer.write(request()).await.unwrap();
+        let buf = &mut [0u8; 6];
+        client.read_exact(buf).await.unwrap();
+        assert_eq!(&[2, 0, 0, 0, 8, 7], buf);
+    }
+
+    #[async_std::test]
+    async fn test_unix() {
+        let path = env::temp_dir().join(format!("discordshim-{}.sock", uuid::Uuid::new_v4()));
+        // A stale socket from a previous run is replaced.
+        drop(bind_unix(&path, None).await.unwrap());
+        let listener = bind_unix(&path, Some(0o660)).await.unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o660, mode & 0o777);
+
+        let limits = ConnectionLimits::default();
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (mut reader, _) = Transport::LengthPrefixed
+            .open(Box::new(server), &limits)
+            .await
+            .unwrap();
+        client.write_all(&[2, 0, 0, 0]).await.unwrap();
+        client.write_all(&response().encode_to_vec()).await.unwrap();
+        assert_eq!(response(), reader.read(&limits).await.unwrap().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_bind_unix_keeps_files() {
+        let path = env::temp_dir().join(format!("discordshim-{}.sock", uuid::Uuid::new_v4()));
+        fs::write(&path, "data").unwrap();
+        assert!(bind_unix(&path, None).await.is_err());
+        assert_eq!("data", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
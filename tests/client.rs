@@ -0,0 +1,306 @@
+use std::{
+    net::{Shutdown, SocketAddr},
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use async_std::{
+    future::timeout,
+    io,
+    net::{TcpListener, TcpStream},
+    task,
+};
+use discordshim::{
+    client::{Backoff, Client, ClientOptions, Endpoint},
+    config::ServerConfig,
+    discord::Discord,
+    fake::FakeDiscord,
+    messages::{
+        EditMessage,
+        EmbedContent,
+        Error,
+        ErrorCode,
+        MessageInfo,
+        Response,
+        Settings,
+        request::Message,
+        response::Field,
+    },
+    server::Server,
+};
+use futures::StreamExt;
+use serenity::{
+    all::ActivityType,
+    model::id::{ChannelId, GuildId, UserId},
+};
+
+const CHANNEL: ChannelId = ChannelId::new(1234);
+const GUILD: GuildId = GuildId::new(99);
+const USER: UserId = UserId::new(42);
+
+struct Shim {
+    discord: Arc<FakeDiscord>,
+    server: Arc<Server>,
+    addr: SocketAddr,
+}
+
+impl Shim {
+    /// Serve a shim on an ephemeral port. `CHANNEL` exists but has to be paired with.
+    async fn start() -> Shim {
+        let discord = Arc::new(FakeDiscord::default());
+        discord.add_channel(CHANNEL, Some(GUILD));
+        let server = Arc::new(Server::new(ServerConfig::default()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = server.clone();
+        let fake = discord.clone();
+        task::spawn(async move { serving.serve(listener, fake).await });
+        Shim {
+            discord,
+            server,
+            addr,
+        }
+    }
+
+    async fn settings(&self) -> Settings {
+        Settings {
+            channel_id: CHANNEL.get(),
+            command_prefix: "/".to_string(),
+            pairing_code: self.server.create_pairing_code(CHANNEL).await,
+            ..Default::default()
+        }
+    }
+
+    async fn command(&self, command: &str) {
+        let info = MessageInfo {
+            channel_id: CHANNEL.get(),
+            ..Default::default()
+        };
+        let bot = self.discord.current_user();
+        self.server
+            .send_command(CHANNEL, USER, bot, command.to_string(), info)
+            .await
+            .unwrap();
+    }
+}
+
+/// Forwards connections to the shim, so tests can cut them.
+struct Proxy {
+    addr: SocketAddr,
+    connections: Arc<Mutex<Vec<TcpStream>>>,
+    refusing: Arc<AtomicBool>,
+}
+
+impl Proxy {
+    async fn start(upstream: SocketAddr) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Mutex::new(vec![]));
+        let refusing = Arc::new(AtomicBool::new(false));
+        let accepted = connections.clone();
+        let refused = refusing.clone();
+        task::spawn(async move {
+            while let Ok((downstream, _)) = listener.accept().await {
+                if refused.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let upstream = TcpStream::connect(upstream).await.unwrap();
+                accepted.lock().unwrap().push(downstream.clone());
+                task::spawn(forward(downstream.clone(), upstream.clone()));
+                task::spawn(forward(upstream, downstream));
+            }
+        });
+        Proxy {
+            addr,
+            connections,
+            refusing,
+        }
+    }
+
+    /// Drop new connections instead of forwarding them.
+    fn refuse(&self, refuse: bool) {
+        self.refusing.store(refuse, Ordering::Relaxed);
+    }
+
+    fn disconnect(&self) {
+        for stream in self.connections.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+async fn forward(from: TcpStream, to: TcpStream) {
+    let _ = io::copy(&mut &from, &mut &to).await;
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+fn options(addr: SocketAddr, settings: Settings) -> ClientOptions {
+    let mut options = ClientOptions::new(Endpoint::Tcp(addr.to_string()), settings);
+    options.reconnect = Some(Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(100),
+    });
+    options
+}
+
+fn embed(title: &str) -> EmbedContent {
+    EmbedContent {
+        title: title.to_string(),
+        ..Default::default()
+    }
+}
+
+fn error_code(error: &color_eyre::Report) -> ErrorCode {
+    error.downcast_ref::<Error>().unwrap().code()
+}
+
+#[async_std::test]
+async fn test_send() {
+    let shim = Shim::start().await;
+    let client = Client::connect(options(shim.addr, shim.settings().await))
+        .await
+        .unwrap();
+
+    let ids = client.send_embed(embed("Printing")).await.unwrap();
+    let files = client.send_file("file.txt", vec![1; 10]).await.unwrap();
+    client.set_presence("Printing").await.unwrap();
+
+    let messages = shim.discord.messages(CHANNEL);
+    assert_eq!(vec![messages[0].id.get()], ids);
+    assert_eq!(vec![messages[1].id.get()], files);
+    assert_eq!("file.txt", messages[1].message.attachments[0].filename);
+    let (activity, _) = shim.discord.presences().pop().unwrap();
+    let activity = activity.unwrap();
+    assert_eq!(ActivityType::Playing, activity.kind);
+    assert_eq!("Printing", activity.name);
+
+    // Errors carry the shim's error code.
+    let edit = EditMessage {
+        message_id: 5678,
+        embed: Some(embed("Done")),
+        file: None,
+    };
+    let response = Response {
+        field: Some(Field::Edit(edit)),
+        ..Default::default()
+    };
+    let error = client.send(response).await.unwrap_err();
+    assert_eq!(ErrorCode::DiscordError, error_code(&error));
+}
+
+#[async_std::test]
+async fn test_requests() {
+    let shim = Shim::start().await;
+    let client = Client::connect(options(shim.addr, shim.settings().await))
+        .await
+        .unwrap();
+    let mut requests = client.requests();
+
+    shim.command("hello").await;
+    shim.command("/status").await;
+    let request = requests.next().await.unwrap();
+    assert_eq!(USER.get(), request.user);
+    assert_eq!(Some(Message::Command("status".to_string())), request.message);
+}
+
+#[async_std::test]
+async fn test_send_latency() {
+    let shim = Shim::start().await;
+    let client = Client::connect(options(shim.addr, shim.settings().await))
+        .await
+        .unwrap();
+
+    // Frames go out as a length and a body, so with Nagle's algorithm each round trip waits for a
+    // delayed ack of about 40ms.
+    let started = Instant::now();
+    for i in 0..50 {
+        client.send_embed(embed(&format!("{i}%"))).await.unwrap();
+    }
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[async_std::test]
+async fn test_rejected() {
+    let shim = Shim::start().await;
+    let settings = Settings {
+        channel_id: CHANNEL.get(),
+        pairing_code: "wrong".to_string(),
+        ..Default::default()
+    };
+    let error = Client::connect(options(shim.addr, settings))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(ErrorCode::InvalidPairingCode, error_code(&error));
+}
+
+#[async_std::test]
+async fn test_reconnect_resumes_session() {
+    let shim = Shim::start().await;
+    let proxy = Proxy::start(shim.addr).await;
+    let client = Client::connect(options(proxy.addr, shim.settings().await))
+        .await
+        .unwrap();
+    let mut requests = client.requests();
+    client.send_embed(embed("Printing")).await.unwrap();
+
+    proxy.disconnect();
+    // The pairing code was used up, so the client is only let back in by resuming its session.
+    let sent = timeout(Duration::from_secs(5), async {
+        while client.send_embed(embed("Done")).await.is_err() {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    });
+    sent.await.unwrap();
+    assert_eq!(2, shim.discord.messages(CHANNEL).len());
+    assert_eq!(1, client.disconnects());
+
+    shim.command("/status").await;
+    let request = requests.next().await.unwrap();
+    assert_eq!(Some(Message::Command("status".to_string())), request.message);
+}
+
+#[async_std::test]
+async fn test_resume_replays_requests() {
+    let shim = Shim::start().await;
+    let proxy = Proxy::start(shim.addr).await;
+    let client = Client::connect(options(proxy.addr, shim.settings().await))
+        .await
+        .unwrap();
+    let mut requests = client.requests();
+
+    proxy.refuse(true);
+    proxy.disconnect();
+    // Give the shim time to notice, so the command is buffered for the detached session.
+    task::sleep(Duration::from_millis(100)).await;
+    shim.command("/status").await;
+    proxy.refuse(false);
+
+    let next = timeout(Duration::from_secs(5), requests.next());
+    let request = next.await.unwrap().unwrap();
+    assert_eq!(Some(Message::Command("status".to_string())), request.message);
+    assert_eq!(USER.get(), request.user);
+    assert_eq!(CHANNEL.get(), request.info.unwrap().channel_id);
+    assert_eq!(1, client.disconnects());
+}
+
+#[async_std::test]
+async fn test_no_reconnect() {
+    let shim = Shim::start().await;
+    let proxy = Proxy::start(shim.addr).await;
+    let mut options = options(proxy.addr, shim.settings().await);
+    options.reconnect = None;
+    let client = Client::connect(options).await.unwrap();
+    let mut requests = client.requests();
+
+    proxy.disconnect();
+    let next = timeout(Duration::from_secs(5), requests.next());
+    assert_eq!(None, next.await.unwrap());
+    assert!(client.send_embed(embed("Printing")).await.is_err());
+}
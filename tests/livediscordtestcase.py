@@ -96,9 +96,8 @@ class LiveDiscordTestCase(TestCase):
             self.client = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
             self.client.settimeout(10)
             self.client.connect((self.discordshim_addr, int(self.discordshim_port)))
-            response = Response(
-                settings=Settings(channel_id=int(self.channel_id))
-            )
+            # Pairing codes are single use, so the shim has to trust the test channel instead.
+            response = Response(settings=Settings(channel_id=int(self.channel_id)))
             data = response.SerializeToString()
             self.client.sendall(len(data).to_bytes(4, byteorder='little'))
             self.client.sendall(data)
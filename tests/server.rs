@@ -0,0 +1,503 @@
+use std::sync::Arc;
+
+use async_std::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use discordshim::{
+    client::{read_request, write_response},
+    config::ServerConfig,
+    discord::Discord,
+    embedbuilder::AttachmentLimits,
+    fake::FakeDiscord,
+    messages::{
+        ActionRow,
+        Button,
+        CommandDefinition,
+        DeleteMessage,
+        EditMessage,
+        EmbedContent,
+        ErrorCode,
+        Interaction,
+        MessageInfo,
+        Presence,
+        ProtoFile,
+        RegisterCommands,
+        Request,
+        Response,
+        Settings,
+        TextField,
+        request::Message,
+        response::Field,
+    },
+    server::Server,
+};
+use serenity::{
+    all::{ActivityType, ComponentInteraction, CreateInteractionResponse, OnlineStatus},
+    model::id::{ChannelId, GuildId, UserId},
+};
+
+const CHANNEL: ChannelId = ChannelId::new(1234);
+const GUILD: GuildId = GuildId::new(99);
+const USER: UserId = UserId::new(42);
+
+struct Shim {
+    discord: Arc<FakeDiscord>,
+    server: Arc<Server>,
+    stream: TcpStream,
+}
+
+impl Shim {
+    /// Serve a shim on an ephemeral port with `CHANNEL` trusted, and connect a client to it.
+    async fn start(mut config: ServerConfig) -> Shim {
+        let discord = Arc::new(FakeDiscord::default());
+        discord.add_channel(CHANNEL, Some(GUILD));
+        config.trusted_channels.push(CHANNEL.get());
+        let server = Arc::new(Server::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = server.clone();
+        let fake = discord.clone();
+        task::spawn(async move { serving.serve(listener, fake).await });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Shim {
+            discord,
+            server,
+            stream,
+        }
+    }
+
+    async fn send(&mut self, id: u64, field: Field) {
+        let response = Response {
+            id,
+            field: Some(field),
+            ..Default::default()
+        };
+        write_response(&mut self.stream, &response).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> Request {
+        read_request(&mut self.stream).await.unwrap()
+    }
+
+    /// Send a Response and wait until it has been handled, returning the IDs of the messages it
+    /// posted, or the error code.
+    async fn request(&mut self, id: u64, field: Field) -> Result<Vec<u64>, ErrorCode> {
+        self.send(id, field).await;
+        match self.recv().await.message {
+            Some(Message::Ack(ack)) if ack.id == id => Ok(ack.message_ids),
+            Some(Message::Error(error)) if error.id == id => Err(error.code()),
+            other => panic!("Expected a reply to {id}, got {other:?}"),
+        }
+    }
+
+    async fn bind(&mut self) {
+        let settings = Settings {
+            channel_id: CHANNEL.get(),
+            command_prefix: "/".to_string(),
+            ..Default::default()
+        };
+        self.request(1, Field::Settings(settings)).await.unwrap();
+    }
+}
+
+fn embed(title: &str) -> Field {
+    Field::Embed(EmbedContent {
+        title: title.to_string(),
+        description: "description".to_string(),
+        ..Default::default()
+    })
+}
+
+fn json<T: serde::Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).unwrap()
+}
+
+/// A click on a button with `custom_id` in `CHANNEL`, as discord would send it.
+fn button_click(custom_id: &str) -> ComponentInteraction {
+    let user = serde_json::json!({
+        "id": USER.to_string(),
+        "username": "user",
+        "discriminator": "0",
+        "avatar": null,
+    });
+    serde_json::from_value(serde_json::json!({
+        "id": "5678",
+        "application_id": "1",
+        "type": 3,
+        "data": {"custom_id": custom_id, "component_type": 2},
+        "channel_id": CHANNEL.to_string(),
+        "user": user,
+        "token": "token",
+        "version": 1,
+        "message": {
+            "id": "4321",
+            "channel_id": CHANNEL.to_string(),
+            "author": user,
+            "content": "",
+            "timestamp": "2024-01-01T00:00:00Z",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        },
+        "locale": "en-US",
+        "entitlements": [],
+        "attachment_size_limit": 0,
+    }))
+    .unwrap()
+}
+
+#[async_std::test]
+async fn test_embed() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    shim.bind().await;
+
+    let ids = shim.request(2, embed("Printing")).await.unwrap();
+    let messages = shim.discord.messages(CHANNEL);
+    assert_eq!(vec![messages[0].id.get()], ids);
+    let embed = json(messages[0].message.embed.as_ref().unwrap());
+    assert_eq!("Printing", embed["title"]);
+    assert_eq!("description", embed["description"]);
+}
+
+#[async_std::test]
+async fn test_file_is_split() {
+    let config = ServerConfig {
+        attachments: AttachmentLimits {
+            max_attachment_size: 1000,
+            chunk_size: 400,
+        },
+        ..Default::default()
+    };
+    let mut shim = Shim::start(config).await;
+    shim.bind().await;
+
+    let small = ProtoFile {
+        filename: "small.txt".to_string(),
+        data: vec![1; 10],
+    };
+    shim.request(2, Field::File(small)).await.unwrap();
+    // Random enough not to compress below the chunk size.
+    let mut seed = 1u32;
+    let data = (0..5000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    let large = ProtoFile {
+        filename: "large.bin".to_string(),
+        data,
+    };
+    let ids = shim.request(3, Field::File(large)).await.unwrap();
+    assert!(ids.len() > 1);
+
+    let messages = shim.discord.messages(CHANNEL);
+    assert_eq!(1 + ids.len(), messages.len());
+    let small = &messages[0].message.attachments[0];
+    assert_eq!("small.txt", small.filename);
+    assert_eq!(vec![1; 10], small.data);
+    assert_eq!("large.bin.zip.000", messages[1].message.attachments[0].filename);
+    for message in &messages[1..] {
+        assert!(message.message.attachments[0].data.len() <= 400);
+    }
+}
+
+#[async_std::test]
+async fn test_edit_and_delete() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    shim.bind().await;
+    let id = shim.request(2, embed("Printing")).await.unwrap()[0];
+
+    let edit = EditMessage {
+        message_id: id,
+        embed: Some(EmbedContent {
+            title: "Done".to_string(),
+            ..Default::default()
+        }),
+        file: None,
+    };
+    assert_eq!(vec![id], shim.request(3, Field::Edit(edit)).await.unwrap());
+    let messages = shim.discord.messages(CHANNEL);
+    assert_eq!(1, messages[0].edits);
+    assert_eq!("Done", json(messages[0].message.embed.as_ref().unwrap())["title"]);
+
+    // Only the shim's own messages can be deleted.
+    let other = shim.discord.add_message(CHANNEL, USER, "hello");
+    let delete = DeleteMessage {
+        message_id: other.get(),
+    };
+    assert_eq!(
+        Err(ErrorCode::MissingAccess),
+        shim.request(4, Field::Delete(delete)).await
+    );
+    let delete = DeleteMessage { message_id: id };
+    shim.request(5, Field::Delete(delete)).await.unwrap();
+    let messages = shim.discord.messages(CHANNEL);
+    assert_eq!(vec![other], messages.iter().map(|m| m.id).collect::<Vec<_>>());
+}
+
+#[async_std::test]
+async fn test_not_paired() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    assert_eq!(
+        Err(ErrorCode::NotPaired),
+        shim.request(1, embed("Printing")).await
+    );
+
+    let settings = Settings {
+        channel_id: 5678,
+        ..Default::default()
+    };
+    assert_eq!(
+        Err(ErrorCode::InvalidPairingCode),
+        shim.request(2, Field::Settings(settings)).await
+    );
+    assert!(shim.discord.messages(CHANNEL).is_empty());
+}
+
+#[async_std::test]
+async fn test_pairing_code() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    let channel = ChannelId::new(5678);
+    shim.discord.add_channel(channel, Some(GUILD));
+    let settings = Settings {
+        channel_id: channel.get(),
+        pairing_code: shim.server.create_pairing_code(channel).await,
+        ..Default::default()
+    };
+    shim.request(1, Field::Settings(settings)).await.unwrap();
+    shim.request(2, embed("Printing")).await.unwrap();
+    assert_eq!(1, shim.discord.messages(channel).len());
+}
+
+#[async_std::test]
+async fn test_commands_are_forwarded() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    shim.bind().await;
+
+    let bot = shim.discord.current_user();
+    let info = MessageInfo {
+        channel_id: CHANNEL.get(),
+        ..Default::default()
+    };
+    let server = shim.server.clone();
+    for command in ["hello", "/status"] {
+        server
+            .send_command(CHANNEL, USER, bot, command.to_string(), info.clone())
+            .await
+            .unwrap();
+    }
+    // Only the message with the client's prefix is forwarded, without the prefix.
+    let request = shim.recv().await;
+    assert_eq!(USER.get(), request.user);
+    assert_eq!(Some(Message::Command("status".to_string())), request.message);
+}
+
+#[async_std::test]
+async fn test_presence_and_slash_commands() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    shim.bind().await;
+
+    let presence = Presence {
+        presence: "Printing benchy".to_string(),
+    };
+    shim.request(2, Field::Presence(presence)).await.unwrap();
+    let (activity, status) = shim.discord.presences().pop().unwrap();
+    let activity = activity.unwrap();
+    assert_eq!("Printing benchy", activity.name);
+    assert_eq!(ActivityType::Playing, activity.kind);
+    assert_eq!(OnlineStatus::Online, status);
+
+    let register = RegisterCommands {
+        commands: vec![CommandDefinition {
+            name: "status".to_string(),
+            description: "Print status".to_string(),
+            ..Default::default()
+        }],
+    };
+    shim.request(3, Field::RegisterCommands(register))
+        .await
+        .unwrap();
+    let commands = shim.discord.commands(GUILD);
+    assert_eq!(1, commands.len());
+    assert_eq!("status", json(&commands[0])["name"]);
+}
+
+#[async_std::test]
+async fn test_interactions() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+
+    // Nobody is bound to follow up, so the user is told instead of the click being deferred.
+    shim.server
+        .send_interaction(shim.discord.as_ref(), button_click("pause"))
+        .await;
+    let responses = shim.discord.interaction_responses();
+    assert!(matches!(
+        responses[0].response,
+        CreateInteractionResponse::Message(_)
+    ));
+
+    shim.bind().await;
+    shim.server
+        .send_interaction(shim.discord.as_ref(), button_click("pause"))
+        .await;
+    let responses = shim.discord.interaction_responses();
+    assert!(matches!(
+        responses[1].response,
+        CreateInteractionResponse::Acknowledge
+    ));
+    let interaction = Interaction {
+        interaction_id: 5678,
+        custom_id: "pause".to_string(),
+        message_id: 4321,
+        values: vec![],
+    };
+    assert_eq!(
+        Some(Message::Interaction(interaction)),
+        shim.recv().await.message
+    );
+}
+
+#[async_std::test]
+async fn test_invalid_custom_id() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    shim.bind().await;
+
+    for custom_id in [String::new(), str::repeat("a", 101)] {
+        let embed = EmbedContent {
+            title: "Printing".to_string(),
+            components: vec![ActionRow {
+                buttons: vec![Button {
+                    custom_id,
+                    label: "Pause".to_string(),
+                    ..Default::default()
+                }],
+                select_menu: None,
+            }],
+            ..Default::default()
+        };
+        let error = shim.request(2, Field::Embed(embed)).await.unwrap_err();
+        assert_eq!(ErrorCode::MalformedMessage, error);
+    }
+    assert!(shim.discord.messages(CHANNEL).is_empty());
+}
+
+#[async_std::test]
+async fn test_unknown_channel() {
+    let mut shim = Shim::start(ServerConfig {
+        trusted_channels: vec![5678],
+        ..Default::default()
+    })
+    .await;
+    // Trusted, but the bot can't see it.
+    let settings = Settings {
+        channel_id: 5678,
+        ..Default::default()
+    };
+    shim.request(1, Field::Settings(settings)).await.unwrap();
+    assert_eq!(
+        Err(ErrorCode::UnknownChannel),
+        shim.request(2, embed("Printing")).await
+    );
+}
+
+fn snapshot() -> ProtoFile {
+    ProtoFile {
+        filename: "filename.png".to_string(),
+        data: std::fs::read("test_data/test_pattern.png").unwrap(),
+    }
+}
+
+#[async_std::test]
+async fn test_send_file() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    shim.bind().await;
+
+    shim.request(2, Field::File(snapshot())).await.unwrap();
+    let messages = shim.discord.messages(CHANNEL);
+    assert_eq!(1, messages.len());
+    assert_eq!(snapshot().data, messages[0].message.attachments[0].data);
+}
+
+#[async_std::test]
+async fn test_send_embed() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    shim.bind().await;
+
+    let textfield = (0..50)
+        .map(|i| TextField {
+            title: i.to_string(),
+            text: String::new(),
+            inline: true,
+        })
+        .collect();
+    let embed = EmbedContent {
+        title: "Title".to_string(),
+        description: "Description".to_string(),
+        author: "Author".to_string(),
+        color: 0x123456,
+        snapshot: Some(snapshot()),
+        textfield,
+        ..Default::default()
+    };
+    let ids = shim.request(2, Field::Embed(embed)).await.unwrap();
+
+    // Discord allows 25 fields per embed, so the embed is split across two messages.
+    let messages = shim.discord.messages(CHANNEL);
+    assert_eq!(2, ids.len());
+    assert_eq!(2, messages.len());
+    let first = json(messages[0].message.embed.as_ref().unwrap());
+    assert_eq!("Title", first["title"]);
+    assert_eq!(0x123456, first["color"]);
+    assert_eq!(25, first["fields"].as_array().unwrap().len());
+    assert_eq!("filename.png", messages[0].message.attachments[0].filename);
+}
+
+#[async_std::test]
+async fn test_recv_message() {
+    let mut shim = Shim::start(ServerConfig::default()).await;
+    shim.bind().await;
+
+    let info = MessageInfo {
+        channel_id: CHANNEL.get(),
+        ..Default::default()
+    };
+    let server = shim.server.clone();
+    let bot = shim.discord.current_user();
+    // Attachments are only forwarded from messages with the client's prefix.
+    for content in ["hello", "/print"] {
+        server
+            .send_file(
+                CHANNEL,
+                USER,
+                bot,
+                content,
+                snapshot(),
+                info.clone(),
+            )
+            .await
+            .unwrap();
+    }
+    server
+        .send_command(CHANNEL, USER, bot, "/status".to_string(), info)
+        .await
+        .unwrap();
+
+    let request = shim.recv().await;
+    assert_eq!(USER.get(), request.user);
+    let Some(Message::File(file)) = request.message else {
+        panic!("Expected a file, got {:?}", request.message);
+    };
+    assert_eq!(snapshot(), file);
+    let request = shim.recv().await;
+    assert_eq!(Some(Message::Command("status".to_string())), request.message);
+}